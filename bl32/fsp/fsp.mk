@@ -30,9 +30,15 @@ FSP_RUST_ROOT		:=	bl32/fsp/kernel
 FSP_RUST_SOURCES	:=	${FSP_RUST_ROOT}/src/console.rs			\
 						${FSP_RUST_ROOT}/src/entrypoints.rs		\
 						${FSP_RUST_ROOT}/src/fsp_alloc.rs		\
+						${FSP_RUST_ROOT}/src/fsp_arena.rs		\
+						${FSP_RUST_ROOT}/src/fsp_buddy.rs		\
+						${FSP_RUST_ROOT}/src/fsp_global.rs		\
+						${FSP_RUST_ROOT}/src/fsp_slab.rs		\
+						${FSP_RUST_ROOT}/src/fsp_slab_box.rs	\
 						${FSP_RUST_ROOT}/src/lib.rs				\
 						${FSP_RUST_ROOT}/src/log.rs				\
 						${FSP_RUST_ROOT}/src/qemu_constants.rs	\
+						${FSP_RUST_ROOT}/src/spinlock.rs		\
 						${FSP_RUST_ROOT}/Cargo.toml
 
 #
@@ -5,18 +5,56 @@ extern crate alloc; // need this due to #![no_std]---for regular Rust, it is by
 use crate::console;
 use crate::debug;
 use crate::fsp_alloc;
+use crate::fsp_arena;
+#[cfg(feature = "buddy")]
+use crate::fsp_buddy;
+use crate::fsp_global;
 use crate::fsp_slab;
 use crate::qemu_constants;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-/// Custom global allocator
+/// Custom global allocator: FSP_SLAB for small requests, FSP_ALLOC for the rest
 #[global_allocator]
-pub static FSP_ALLOC: fsp_alloc::FspAlloc = fsp_alloc::FspAlloc::new();
+pub static FSP_GLOBAL: fsp_global::FspGlobalAlloc = fsp_global::FspGlobalAlloc;
+
+/// Heap, with an arena for every core
+pub static FSP_ALLOC: fsp_arena::FspArenas = fsp_arena::FspArenas::new();
+
+/// Page-frame allocator for slab pages, with the buddy feature
+#[cfg(feature = "buddy")]
+pub static FSP_PAGES: fsp_buddy::FspBuddy = fsp_buddy::FspBuddy::new();
 
 pub static mut FSP_SLAB : fsp_slab::KmemCache = fsp_slab::KmemCache::new();
 /// Global console
 // TODO: Find a way to avoid static mut
 pub static mut FSP_CONSOLE: console::FspConsole = console::FspConsole::new();
 
+/// What is tracked of an SMC with the heap_track feature, see smc_track_begin().
+#[cfg(feature = "heap_track")]
+#[derive(Clone, Copy)]
+pub struct SmcTrack {
+    func: u64,         // function ID of the SMC
+    tag: usize,        // tag of the buffers allocated for it, 0 if there is no SMC
+    checkpoint: usize, // checkpoint taken when it started
+    allocs: usize,     // fsp_alloc::tagged_allocs() when it started
+}
+
+#[cfg(feature = "heap_track")]
+impl SmcTrack {
+    /// No SMC is being handled.
+    pub const NONE: SmcTrack = SmcTrack {
+        func: 0,
+        tag: 0,
+        checkpoint: 0,
+        allocs: 0,
+    };
+}
+
+/// The SMC being handled on each core, with the heap_track feature.
+#[cfg(feature = "heap_track")]
+static mut SMC_TRACKS: [SmcTrack; qemu_constants::PLATFORM_CORE_COUNT] =
+    [SmcTrack::NONE; qemu_constants::PLATFORM_CORE_COUNT];
+
 /// This is the initialization function that should be called first before anything else.
 fn fsp_init() {
     unsafe {
@@ -31,11 +69,40 @@ fn fsp_init() {
     } else {
         base = qemu_constants::BL32_MEM_BASE;
     };
+
+    // With the buddy feature, the top of the secure memory is for pages, and the rest is for
+    // FSP_ALLOC.
+    #[cfg(feature = "buddy")]
+    {
+        size -= fsp_buddy::MAX_POOL_SIZE;
+        FSP_PAGES.init(base + size, fsp_buddy::MAX_POOL_SIZE);
+    }
     FSP_ALLOC.init(base, size);
 
     unsafe{
         FSP_SLAB.main_init();    
     }
+    // Without the buddy feature, the slab pages come from FSP_ALLOC, so it can get the free ones
+    // back before running out of memory.
+    #[cfg(not(feature = "buddy"))]
+    FSP_ALLOC.bectl(Some(slab_compact), None, None, 0);
+}
+
+/// Number of times slab_compact() asks for a retry in a row for one request while another core
+/// holds FSP_SLAB.
+#[cfg(not(feature = "buddy"))]
+const SLAB_COMPACT_MAX_BUSY: usize = 64;
+
+/// Compaction function of FSP_ALLOC. The free slabs are all reaped at once, so it asks for a retry
+/// when it freed some, or when another core holds FSP_SLAB and there may be some to free once it
+/// is done. The latter only goes on for SLAB_COMPACT_MAX_BUSY calls, so that a request doesn't
+/// spin on a busy FSP_SLAB instead of failing.
+#[cfg(not(feature = "buddy"))]
+fn slab_compact(_size: usize, seq: usize) -> bool {
+    match unsafe { FSP_SLAB.reap() } {
+        Some(num) => num > 0,
+        None => seq < SLAB_COMPACT_MAX_BUSY,
+    }
 }
 
 /// This is the actual main function that extern_c_defs::fsp_main_wrapper() calls.
@@ -45,16 +112,183 @@ pub fn fsp_main() {
     debug!("fsp main");
    
     mem_test();
+    assert!(FSP_ALLOC.validate());
+    realloc_test();
+    #[cfg(feature = "segregated_fit")]
+    reuse_test();
+    region_test();
+    bectl_test();
+
+    #[cfg(feature = "buddy")]
+    page_test();
 
     unsafe{
         slab_test();
     }
+    #[cfg(feature = "heap_track")]
+    track_test();
+    assert!(FSP_ALLOC.validate());
     mem_test();
+    assert!(FSP_ALLOC.validate());
+
+    print_heap_stats();
+    print_slab_info();
 
     debug!("fsp main done");
 }
 
+/// This is the main function for a secondary core that has just been turned on, which
+/// lib::cpu_on_main_wrapper() calls.
+pub fn fsp_cpu_on_main() {
+    let linear_id = unsafe { crate::plat_my_core_pos() };
+    debug!("fsp cpu {} on", linear_id);
+
+    // The cores may be turned on one after the other, but each one runs this while the ones before
+    // it may already be handling SMCs.
+    #[cfg(feature = "smp_test")]
+    unsafe {
+        smp_mem_test(linear_id as usize);
+    }
+
+    debug!("fsp cpu {} on done", linear_id);
+}
+
+/// This is called by lib::cpu_off_main_wrapper() before the current core is turned off. The
+/// objects the core keeps in its slab magazines go back to the slabs, for the other cores to use.
+pub fn fsp_cpu_off_main() {
+    let linear_id = unsafe { crate::plat_my_core_pos() };
+    debug!("fsp cpu {} off", linear_id);
+
+    unsafe {
+        FSP_SLAB.flush_magazines();
+    }
+}
+
+/// Like fsp_cpu_off_main(), this is called by lib::cpu_suspend_main_wrapper() before the current
+/// core is suspended.
+pub fn fsp_cpu_suspend_main() {
+    let linear_id = unsafe { crate::plat_my_core_pos() };
+    debug!("fsp cpu {} suspend", linear_id);
+
+    unsafe {
+        FSP_SLAB.flush_magazines();
+    }
+}
+
+/// Starts tracking the buffers allocated for the SMC `func` on the current core, which are tagged
+/// with the core and `func`. Returns the tracking state of the SMC that this one preempted, if
+/// any, for smc_track_end().
+#[cfg(feature = "heap_track")]
+pub fn smc_track_begin(func: u64) -> SmcTrack {
+    let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+    let track = SmcTrack {
+        func,
+        tag: ((linear_id + 1) << 32) | (func as usize & 0xffffffff),
+        checkpoint: fsp_alloc::checkpoint(),
+        allocs: fsp_alloc::tagged_allocs(),
+    };
+    fsp_alloc::set_tag(track.tag);
+    unsafe { core::mem::replace(&mut SMC_TRACKS[linear_id], track) }
+}
+
+/// Reports the buffers that the SMC tracked on the current core left allocated, and goes back to
+/// tracking `prev`, as smc_track_begin() returned it. Returns the number of buffers reported. The
+/// heap is only walked if the SMC allocated something.
+///
+/// An aborted SMC never gets here, so its abort handler calls this with SmcTrack::NONE instead.
+/// The SMCs that preempted it have all ended by then, so the SMC tracked on the core is the
+/// aborted one.
+#[cfg(feature = "heap_track")]
+pub fn smc_track_end(prev: SmcTrack) -> usize {
+    let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+    let track = unsafe { core::mem::replace(&mut SMC_TRACKS[linear_id], prev) };
+    fsp_alloc::set_tag(prev.tag);
+    if track.tag == 0 || fsp_alloc::tagged_allocs() == track.allocs {
+        return 0;
+    }
+
+    let live = FSP_ALLOC.report(track.checkpoint, Some(track.tag));
+    if live != 0 {
+        debug!("SMC {:#x} left {} buffers allocated", track.func, live);
+    }
+    live
+}
+
+/// Prints the heap statistics of FSP_ALLOC on the console.
+fn print_heap_stats() {
+    let stats = FSP_ALLOC.stats();
+    debug!(
+        "heap: allocated {} (max {}), free {} in {} blocks (largest {}), {} allocs, {} frees",
+        stats.cur_alloc,
+        stats.max_alloc,
+        stats.tot_free,
+        stats.num_free,
+        stats.max_free,
+        stats.num_get,
+        stats.num_rel
+    );
+}
+
+/// Returns the pair of heap statistics chosen by `selector` for the FSP_HEAP_STATS SMC, or None
+/// if there is no such pair.
+///
+/// - 0: bytes currently allocated, high-water mark of the bytes allocated
+/// - 1: total free bytes, largest free block
+/// - 2: number of free blocks, 0
+/// - 3: number of allocations, number of deallocations
+pub fn heap_stats_smc(selector: u64) -> Option<(u64, u64)> {
+    let stats = FSP_ALLOC.stats();
+    match selector {
+        0 => Some((stats.cur_alloc as u64, stats.max_alloc as u64)),
+        1 => Some((stats.tot_free as u64, stats.max_free as u64)),
+        2 => Some((stats.num_free as u64, 0)),
+        3 => Some((stats.num_get as u64, stats.num_rel as u64)),
+        _ => None,
+    }
+}
+
+/// Prints the statistics of every slab cache, like /proc/slabinfo.
+fn print_slab_info() {
+    debug!("slabinfo: name, objects in use/total, slot size, full/partial/free slabs, overhead");
+    let mut index = 0;
+    while let Some(info) = unsafe { FSP_SLAB.cache_info(index) } {
+        debug!(
+            "slab {}: {}/{} objects of {}, slabs {}/{}/{}, overhead {}",
+            if info.name().is_empty() { "kmem" } else { info.name() },
+            info.obj_active,
+            info.obj_total,
+            info.obj_size,
+            info.slabs_full,
+            info.slabs_partial,
+            info.slabs_free,
+            info.overhead
+        );
+        index += 1;
+    }
+}
+
+/// Returns the pair of statistics chosen by `selector` of the slab cache at position `index` for
+/// the FSP_SLAB_INFO SMC, or None if there is no such cache or pair. The caches are numbered from
+/// 0 in the order print_slab_info() prints them.
+///
+/// - 0: name as up to 8 ASCII bytes, the first one in the low byte (0 if unnamed), slot size
+/// - 1: objects in use, total objects
+/// - 2: full slabs, partial slabs
+/// - 3: free slabs, overhead in bytes
+pub fn slab_info_smc(index: u64, selector: u64) -> Option<(u64, u64)> {
+    let info = unsafe { FSP_SLAB.cache_info(index as usize) }?;
+    match selector {
+        0 => Some((u64::from_le_bytes(info.name), info.obj_size as u64)),
+        1 => Some((info.obj_active as u64, info.obj_total as u64)),
+        2 => Some((info.slabs_full as u64, info.slabs_partial as u64)),
+        3 => Some((info.slabs_free as u64, info.overhead as u64)),
+        _ => None,
+    }
+}
+
 fn mem_test() {
+    use crate::fsp_alloc::AllocError;
+    use alloc::alloc::{GlobalAlloc, Layout};
     use alloc::boxed::Box;
     use alloc::string::String;
     use alloc::string::ToString;
@@ -105,20 +339,334 @@ fn mem_test() {
         }
     }
 
+    // Aligned requests come back aligned, also once the buffers around them have been freed and
+    // the free blocks are no longer where init() put them.
+    let mut bufs: Vec<(usize, Layout)> = Vec::new();
+    for round in 0..3 {
+        for align in [16, 64, 4096].iter() {
+            for size in [8, 100, 5000].iter() {
+                let layout = Layout::from_size_align(*size + round, *align).unwrap();
+                let buf = unsafe { FSP_ALLOC.alloc(layout) };
+                assert!(!buf.is_null() && buf as usize % *align == 0);
+                unsafe { core::ptr::write_bytes(buf, 0xa5, layout.size()) };
+                bufs.push((buf as usize, layout));
+            }
+        }
+        let mut i = 0;
+        bufs.retain(|&(buf, layout)| {
+            i += 1;
+            if i % 2 == 0 {
+                return true;
+            }
+            unsafe {
+                assert_eq!(*((buf + layout.size() - 1) as *const u8), 0xa5);
+                FSP_ALLOC.dealloc(buf as *mut u8, layout);
+            }
+            false
+        });
+    }
+    for (buf, layout) in bufs {
+        unsafe { FSP_ALLOC.dealloc(buf as *mut u8, layout) };
+    }
+
+    // A Box of an over-aligned type big enough to be served by FSP_ALLOC.
+    #[repr(align(64))]
+    struct Aligned([u8; 512]);
+    let boxes: Vec<Box<Aligned>> = (0..16).map(|i| Box::new(Aligned([i; 512]))).collect();
+    for (i, aligned) in boxes.iter().enumerate() {
+        assert_eq!(&**aligned as *const Aligned as usize % 64, 0);
+        assert_eq!(aligned.0[511], i as u8);
+    }
+    drop(boxes);
+
+    // Requests that can't be satisfied fail with an error instead of ending up in
+    // alloc_error_handler.
+    let layout = Layout::from_size_align(qemu_constants::BL32_MEM_SIZE, 8).unwrap();
+    assert_eq!(FSP_ALLOC.try_alloc(layout), Err(AllocError::OutOfMemory));
+    let layout = Layout::from_size_align(0, 8).unwrap();
+    assert_eq!(FSP_ALLOC.try_alloc(layout), Err(AllocError::BadLayout));
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    match FSP_ALLOC.try_alloc(layout) {
+        Ok(buf) => unsafe { FSP_ALLOC.dealloc(buf.as_ptr(), layout) },
+        Err(err) => panic!("try_alloc failed: {:?}", err),
+    }
+
     debug!("mem_test done");
 }
 
+/// Memory of the private FspAlloc of realloc_test().
+static mut REALLOC_POOL: [u8; 16384] = [0; 16384];
+static REALLOC_ALLOC: fsp_alloc::FspAlloc = fsp_alloc::FspAlloc::new();
+
+/// Resizes buffers of an FspAlloc of its own, so that it knows where the blocks are: a buffer that
+/// has to move, then one that grows into the free buffer after it, and one that shrinks.
+fn realloc_test() {
+    use alloc::alloc::{GlobalAlloc, Layout};
+
+    debug!("realloc_test");
+    let heap = &REALLOC_ALLOC;
+    let filled =
+        |buf: *mut u8, len: usize, byte: u8| (0..len).all(|i| unsafe { *buf.add(i) } == byte);
+    unsafe {
+        heap.init(REALLOC_POOL.as_mut_ptr() as usize, REALLOC_POOL.len());
+
+        // Both come from the end of the single free block, so b is right in front of a.
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let a = heap.alloc(layout);
+        let b = heap.alloc(layout);
+        assert!(!a.is_null() && !b.is_null() && (b as usize) < (a as usize));
+        core::ptr::write_bytes(b, 0x11, 256);
+
+        // b can't grow into a, so it moves to the end of the free block, right in front of it.
+        let b2 = heap.realloc(b, layout, 1024);
+        assert!(!b2.is_null() && b2 != b);
+        assert!(filled(b2, 256, 0x11));
+
+        // The old b is free now, so b2 grows into it.
+        core::ptr::write_bytes(b2, 0x22, 1024);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        assert_eq!(heap.realloc(b2, layout, 1224), b2);
+        assert!(filled(b2, 1024, 0x22));
+
+        // Shrinking stays in place and gives the tail back.
+        let cur_alloc = heap.stats().cur_alloc;
+        let layout = Layout::from_size_align(1224, 8).unwrap();
+        assert_eq!(heap.realloc(b2, layout, 64), b2);
+        assert!(filled(b2, 64, 0x22));
+        assert!(heap.stats().cur_alloc < cur_alloc);
+
+        heap.dealloc(b2, Layout::from_size_align(64, 8).unwrap());
+        heap.dealloc(a, Layout::from_size_align(256, 8).unwrap());
+    }
+    assert!(heap.validate());
+    assert_eq!(heap.stats().cur_alloc, 0);
+    debug!("realloc_test done");
+}
+
+/// Memory of the private FspAlloc of reuse_test().
+#[cfg(feature = "segregated_fit")]
+static mut REUSE_POOL: [u8; 4096] = [0; 4096];
+#[cfg(feature = "segregated_fit")]
+static REUSE_ALLOC: fsp_alloc::FspAlloc = fsp_alloc::FspAlloc::new();
+
+/// Releases a buffer between two allocated ones in an FspAlloc of its own, and checks that the
+/// next request of the same size gets it back rather than a piece of the big free block that is
+/// still there.
+#[cfg(feature = "segregated_fit")]
+fn reuse_test() {
+    use alloc::alloc::{GlobalAlloc, Layout};
+
+    debug!("reuse_test");
+    let heap = &REUSE_ALLOC;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    unsafe {
+        heap.init(REUSE_POOL.as_mut_ptr() as usize, REUSE_POOL.len());
+        let a = heap.alloc(layout);
+        let b = heap.alloc(layout);
+        let c = heap.alloc(layout);
+        assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+        // b can't be merged with a or c, so it stays on the free list of its size.
+        heap.dealloc(b, layout);
+        for _ in 0..4 {
+            let again = heap.alloc(layout);
+            assert_eq!(again, b);
+            heap.dealloc(again, layout);
+        }
+
+        heap.dealloc(a, layout);
+        heap.dealloc(c, layout);
+    }
+    assert!(heap.validate());
+    assert_eq!(heap.stats().cur_alloc, 0);
+    debug!("reuse_test done");
+}
+
+/// Memory of the private FspAlloc of region_test(), one region for init() and one for
+/// add_region().
+static mut REGION_POOLS: [[u8; 4096]; 2] = [[0; 4096]; 2];
+static REGION_ALLOC: fsp_alloc::FspAlloc = fsp_alloc::FspAlloc::new();
+
+/// Exhausts an FspAlloc of its own, then adds a second region to it, and checks that the buffers
+/// allocated from then on come from that region.
+fn region_test() {
+    use crate::fsp_alloc::AllocError;
+    use alloc::alloc::{GlobalAlloc, Layout};
+    use alloc::vec::Vec;
+
+    debug!("region_test");
+    let heap = &REGION_ALLOC;
+    let layout = Layout::from_size_align(200, 8).unwrap();
+    let mut bufs: Vec<usize> = Vec::new();
+    unsafe {
+        let first = REGION_POOLS[0].as_mut_ptr() as usize;
+        let second = REGION_POOLS[1].as_mut_ptr() as usize;
+        heap.init(first, 4096);
+        while let Ok(buf) = heap.try_alloc(layout) {
+            let buf = buf.as_ptr() as usize;
+            assert!(buf >= first && buf + 200 <= first + 4096);
+            bufs.push(buf);
+        }
+        assert!(!bufs.is_empty());
+        assert_eq!(heap.try_alloc(layout), Err(AllocError::OutOfMemory));
+
+        heap.add_region(second, 4096);
+        for _i in 0..8 {
+            let buf = heap.try_alloc(layout).unwrap().as_ptr() as usize;
+            assert!(buf >= second && buf + 200 <= second + 4096);
+            bufs.push(buf);
+        }
+
+        for buf in bufs {
+            heap.dealloc(buf as *mut u8, layout);
+        }
+    }
+    assert!(heap.validate());
+    assert_eq!(heap.stats().cur_alloc, 0);
+    debug!("region_test done");
+}
+
+/// Memory of the private FspAlloc of bectl_test(), and the spare memory that its acquisition
+/// function hands out pools from.
+static mut BECTL_POOL: [u8; 4096] = [0; 4096];
+static mut BECTL_SPARE: [u8; 16384] = [0; 16384];
+static BECTL_ALLOC: fsp_alloc::FspAlloc = fsp_alloc::FspAlloc::new();
+
+/// Bytes of BECTL_SPARE handed out so far, and the number of pools and bytes given back.
+static BECTL_SPARE_USED: AtomicUsize = AtomicUsize::new(0);
+static BECTL_RELEASED: AtomicUsize = AtomicUsize::new(0);
+static BECTL_RELEASED_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Acquisition function of bectl_test(). The pools are handed out back to back, so most of them
+/// are not aligned.
+fn bectl_acquire(len: usize) -> *mut u8 {
+    let used = BECTL_SPARE_USED.load(Ordering::SeqCst);
+    if used + len > unsafe { BECTL_SPARE.len() } {
+        return core::ptr::null_mut();
+    }
+    BECTL_SPARE_USED.store(used + len, Ordering::SeqCst);
+    unsafe { BECTL_SPARE.as_mut_ptr().add(used) }
+}
+
+/// Release function of bectl_test().
+fn bectl_release(pool: *mut u8, len: usize) {
+    let spare = unsafe { BECTL_SPARE.as_ptr() } as usize;
+    assert!(
+        pool as usize >= spare
+            && pool as usize + len <= spare + BECTL_SPARE_USED.load(Ordering::SeqCst)
+    );
+    BECTL_RELEASED.fetch_add(1, Ordering::SeqCst);
+    BECTL_RELEASED_LEN.fetch_add(len, Ordering::SeqCst);
+}
+
+/// Exhausts an FspAlloc of its own whose pools are sized to the requests, checks that the next
+/// requests get new pools from the acquisition function, then frees everything and checks that
+/// those pools are given back to the release function.
+fn bectl_test() {
+    use alloc::alloc::{GlobalAlloc, Layout};
+    use alloc::vec::Vec;
+
+    debug!("bectl_test");
+    let heap = &BECTL_ALLOC;
+    let tiny = Layout::from_size_align(8, 8).unwrap();
+    let aligned = Layout::from_size_align(1000, 64).unwrap();
+    let mut bufs: Vec<usize> = Vec::new();
+    unsafe {
+        heap.init(BECTL_POOL.as_mut_ptr() as usize, BECTL_POOL.len());
+        heap.bectl(None, Some(bectl_acquire), Some(bectl_release), 0);
+
+        // Nothing is left in the pool, not even a block for the smallest buffer.
+        loop {
+            let buf = heap.alloc_from_pools(tiny);
+            if buf.is_null() {
+                break;
+            }
+            bufs.push(buf as usize);
+        }
+        assert_eq!(BECTL_SPARE_USED.load(Ordering::SeqCst), 0);
+
+        // Each of these gets a pool just big enough for it.
+        let buf = heap.alloc(tiny);
+        assert!(!buf.is_null());
+        let first_pool_len = BECTL_SPARE_USED.load(Ordering::SeqCst);
+        assert!(first_pool_len > 0);
+        let aligned_buf = heap.alloc(aligned);
+        assert!(!aligned_buf.is_null() && aligned_buf as usize % 64 == 0);
+        assert!(BECTL_SPARE_USED.load(Ordering::SeqCst) > first_pool_len);
+
+        // The first pool isn't an acquired one, so only the two others are released.
+        for buf in bufs {
+            heap.dealloc(buf as *mut u8, tiny);
+        }
+        assert_eq!(BECTL_RELEASED.load(Ordering::SeqCst), 0);
+        heap.dealloc(buf, tiny);
+        assert_eq!(BECTL_RELEASED.load(Ordering::SeqCst), 1);
+        heap.dealloc(aligned_buf, aligned);
+        assert_eq!(BECTL_RELEASED.load(Ordering::SeqCst), 2);
+    }
+    assert_eq!(
+        BECTL_RELEASED_LEN.load(Ordering::SeqCst),
+        BECTL_SPARE_USED.load(Ordering::SeqCst)
+    );
+    assert!(heap.validate());
+    assert_eq!(heap.stats().cur_alloc, 0);
+    debug!("bectl_test done");
+}
+
+/// Leaks a buffer on purpose from pretend SMCs, and checks that it is the only buffer reported,
+/// even though the SMC also made a slab cache grow.
+#[cfg(feature = "heap_track")]
+fn track_test() {
+    use alloc::alloc::{GlobalAlloc, Layout};
+
+    debug!("track_test");
+    let layout = Layout::from_size_align(100, 8).unwrap();
+
+    // An SMC that allocates nothing has nothing to report.
+    let prev = smc_track_begin(0x2000);
+    assert_eq!(smc_track_end(prev), 0);
+
+    let prev = smc_track_begin(0x2001);
+    let leaked = unsafe { FSP_ALLOC.alloc(layout) };
+    assert!(!leaked.is_null());
+    unsafe {
+        let freed = FSP_ALLOC.alloc(layout);
+        assert!(!freed.is_null());
+        FSP_ALLOC.dealloc(freed, layout);
+    }
+    let cache = unsafe { FSP_SLAB.create_cache("track_test", 64, 8, None, None) }.unwrap();
+    let obj = unsafe { cache.cache_alloc() };
+    assert!(!obj.is_null());
+    assert_eq!(smc_track_end(prev), 1);
+
+    // An aborted SMC is ended from its abort handler, which doesn't know what it was.
+    smc_track_begin(0x2002);
+    let aborted = unsafe { FSP_ALLOC.alloc(layout) };
+    assert!(!aborted.is_null());
+    assert_eq!(smc_track_end(SmcTrack::NONE), 1);
+
+    unsafe {
+        FSP_ALLOC.dealloc(leaked, layout);
+        FSP_ALLOC.dealloc(aborted, layout);
+        cache.cache_dealloc(obj);
+        assert!(FSP_SLAB.destroy_cache(cache, false));
+    }
+    debug!("track_test done");
+}
+
  unsafe fn  slab_test (){
      //test for speed
+    use crate::fsp_slab::KMEM_MAX_SIZE;
+    use crate::fsp_slab_box::{SlabBox, TypedCache};
     use alloc::vec::Vec;
     use alloc::alloc::{Layout};
-    use alloc::alloc::{alloc,dealloc};
+    use alloc::alloc::GlobalAlloc;
 
     debug!("testing allco speed");
     let _layout = Layout::from_size_align_unchecked(16, 2);
     for _i in 1..1000000{
-        let a = alloc(_layout);
-        dealloc(a,_layout);
+        let a = FSP_ALLOC.alloc(_layout);
+        FSP_ALLOC.dealloc(a,_layout);
     }
     debug!("testing allco done");
 
@@ -152,6 +700,301 @@ fn mem_test() {
         }
     }
 
+    debug!("testing magazines");
+    //an object that is freed stays in the magazine of the core, and the next allocation of its size gets it back
+    let ptr = FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(40, 8));
+    FSP_SLAB.kmem_dealloc(ptr, Layout::from_size_align_unchecked(40, 8));
+    assert_eq!(FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(40, 8)), ptr);
+    FSP_SLAB.kmem_dealloc(ptr, Layout::from_size_align_unchecked(40, 8));
+    FSP_SLAB.flush_magazines();
+    debug!("testing magazines done");
+
+    debug!("testing slab reaping");
+    //the free slabs of a cache are kept up to its limit, and reap() gives them all back
+    let reaped = FSP_SLAB.create_cache("reaped", 200, 8, None, None).unwrap();
+    reaped.set_free_limit(4);
+    let mut objs: Vec<usize> = Vec::new();
+    for _i in 0..200{
+        objs.push(reaped.cache_alloc() as usize);
+    }
+    for obj in objs.into_iter(){
+        reaped.cache_dealloc(obj as *mut u8);
+    }
+    FSP_SLAB.flush_magazines();
+    assert!(FSP_SLAB.reap().unwrap() >= 4);
+    assert_eq!(FSP_SLAB.reap(), Some(0));
+    debug!("testing slab reaping done");
+
+    debug!("testing slabinfo");
+    //the objects in use are counted, but not the ones in the magazines
+    let counted = FSP_SLAB.create_cache("counted", 100, 8, None, None).unwrap();
+    let mut objs: Vec<usize> = Vec::new();
+    for _i in 0..50{
+        objs.push(counted.cache_alloc() as usize);
+    }
+    let info = FSP_SLAB.cache_info(0).unwrap();
+    assert_eq!(info.name(), "counted");
+    assert_eq!(info.obj_active, 50);
+    assert!(info.obj_total >= 50 && info.slabs_full + info.slabs_partial > 0);
+    assert_eq!(slab_info_smc(0, 1), Some((50, info.obj_total as u64)));
+    for obj in objs.into_iter(){
+        counted.cache_dealloc(obj as *mut u8);
+    }
+    assert_eq!(FSP_SLAB.cache_info(0).unwrap().obj_active, 0);
+    print_slab_info();
+    debug!("testing slabinfo done");
+
+    debug!("testing big objects");
+    let mut objs: Vec<(usize, usize)> = Vec::new();
+    let mut size = 100;
+    while size <= KMEM_MAX_SIZE{
+        for _i in 0..20{
+            let ptr = FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(size, 8));
+            assert!(!ptr.is_null());
+            core::ptr::write_bytes(ptr, _i as u8, size);
+            objs.push((ptr as usize, size));
+        }
+        size = size * 3 / 2;
+    }
+    for (_i, (ptr, size)) in objs.into_iter().enumerate(){
+        assert_eq!(*(ptr as *mut u8), (_i % 20) as u8);
+        assert_eq!(*((ptr + size - 1) as *mut u8), (_i % 20) as u8);
+        FSP_SLAB.kmem_dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(size, 8));
+    }
+    assert!(FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(KMEM_MAX_SIZE + 1, 8)).is_null());
+    debug!("testing big objects done");
+
+    debug!("testing aligned objects");
+    let mut objs: Vec<(usize, Layout)> = Vec::new();
+    let mut align = 16;
+    while align <= 4096{
+        for size in [8, align / 2, align, align + 8].iter(){
+            let layout = Layout::from_size_align_unchecked(*size, align);
+            let ptr = FSP_SLAB.kmem_alloc(layout);
+            assert!(!ptr.is_null() && ptr as usize % align == 0);
+            core::ptr::write_bytes(ptr, 0x3c, *size);
+            objs.push((ptr as usize, layout));
+        }
+        align *= 2;
+    }
+    for (ptr, layout) in objs.into_iter(){
+        assert_eq!(*((ptr + layout.size() - 1) as *mut u8), 0x3c);
+        FSP_SLAB.kmem_dealloc(ptr as *mut u8, layout);
+    }
+    let aligned = FSP_SLAB.create_cache("aligned", 40, 64, None, None).unwrap();
+    let ptr = aligned.cache_alloc();
+    assert!(!ptr.is_null() && ptr as usize % 64 == 0);
+    aligned.cache_dealloc(ptr);
+    debug!("testing aligned objects done");
+
+    debug!("testing named caches");
+    let sessions = FSP_SLAB.create_cache("session", 48, 8, None, None).unwrap();
+    let timers = FSP_SLAB.create_cache("timer", 48, 8, None, None).unwrap();
+    let mut objs: Vec<(usize, usize)> = Vec::new();
+    for _i in 0..500{
+        let session = sessions.cache_alloc();
+        let timer = timers.cache_alloc();
+        *(session as *mut usize) = _i;
+        *(timer as *mut usize) = !_i;
+        objs.push((session as usize, timer as usize));
+    }
+    //objects of the same size from kmem_alloc() don't come from the named caches
+    let ptr = FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(48, 8));
+    for &(session, timer) in objs.iter(){
+        assert!(ptr as usize != session && ptr as usize != timer);
+    }
+    FSP_SLAB.kmem_dealloc(ptr, Layout::from_size_align_unchecked(48, 8));
+    for (_i, (session, timer)) in objs.into_iter().enumerate(){
+        assert_eq!(*(session as *mut usize), _i);
+        assert_eq!(*(timer as *mut usize), !_i);
+        sessions.cache_dealloc(session as *mut u8);
+        timers.cache_dealloc(timer as *mut u8);
+    }
+    debug!("testing named caches done");
+
+    debug!("testing typed caches");
+    struct Session {
+        id: usize,
+        key: [u8; 32],
+    }
+    let cache: TypedCache<Session> = TypedCache::new("sessions").unwrap();
+    let mut boxes: Vec<SlabBox<Session>> = Vec::new();
+    for _i in 0..300{
+        match cache.alloc(Session { id: _i, key: [_i as u8; 32] }){
+            Ok(session) => boxes.push(session),
+            Err(_) => panic!("slab box alloc"),
+        }
+    }
+    for session in boxes.iter_mut(){
+        session.key[0] = !session.key[0];
+    }
+    for (_i, session) in boxes.iter().enumerate(){
+        assert_eq!(session.id, _i);
+        assert_eq!(session.key[0], !(_i as u8));
+        assert_eq!(session.key[31], _i as u8);
+    }
+    drop(boxes);
+    drop(cache);
+    let mut index = 0;
+    while let Some(info) = FSP_SLAB.cache_info(index){
+        assert!(info.name() != "sessions");
+        index += 1;
+    }
+    debug!("testing typed caches done");
+
+    debug!("testing constructed caches");
+    //the constructor fills the whole object with a pattern that the users must leave in place
+    fn ctor(obj: *mut u8){
+        unsafe { core::ptr::write_bytes(obj, 0x5a, 40); }
+    }
+    let buffers = FSP_SLAB.create_cache("buffers", 40, 8, Some(ctor), None).unwrap();
+    let mut objs: Vec<usize> = Vec::new();
+    for _i in 0..500{
+        let obj = buffers.cache_alloc();
+        for _j in 0..40{
+            assert_eq!(*obj.add(_j), 0x5a);
+        }
+        objs.push(obj as usize);
+    }
+    for obj in objs.into_iter(){
+        buffers.cache_dealloc(obj as *mut u8);
+    }
+    for _i in 0..500{
+        let obj = buffers.cache_alloc();
+        assert_eq!(*obj, 0x5a);
+        assert_eq!(*obj.add(39), 0x5a);
+        buffers.cache_dealloc(obj);
+    }
+    debug!("testing constructed caches done");
+
+    debug!("testing destroyed caches");
+    let doomed = FSP_SLAB.create_cache("doomed", 64, 8, None, None).unwrap();
+    let mut objs: Vec<usize> = Vec::new();
+    for _i in 0..200{
+        objs.push(doomed.cache_alloc() as usize);
+    }
+    assert!(!FSP_SLAB.destroy_cache(doomed, false));
+    for obj in objs.into_iter(){
+        doomed.cache_dealloc(obj as *mut u8);
+    }
+    assert!(FSP_SLAB.destroy_cache(doomed, false));
+    let mut index = 0;
+    while let Some(info) = FSP_SLAB.cache_info(index){
+        assert!(info.name() != "doomed");
+        index += 1;
+    }
+    let forced = FSP_SLAB.create_cache("forced", 64, 8, None, None).unwrap();
+    for _i in 0..200{
+        assert!(!forced.cache_alloc().is_null());
+    }
+    assert!(FSP_SLAB.destroy_cache(forced, true));
+    debug!("testing destroyed caches done");
+
+}
+
+/// Secondary cores that have run smp_mem_test(), one bit per core.
+#[cfg(feature = "smp_test")]
+static SMP_TEST_DONE: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates from FSP_ALLOC and FSP_SLAB on the current core while other cores do the same,
+/// writing a pattern unique to the core into every buffer and checking it is still there before
+/// releasing it. Memory handed out to two cores at once shows up as a broken pattern.
+///
+/// Each core only runs it the first time it is turned on, and doesn't wait for the others: the
+/// cores that the normal world turns on at once run it together.
+#[cfg(feature = "smp_test")]
+unsafe fn smp_mem_test(linear_id: usize) {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use alloc::vec::Vec;
+
+    if SMP_TEST_DONE.fetch_or(1 << linear_id, Ordering::SeqCst) & (1 << linear_id) != 0 {
+        return;
+    }
+    debug!("smp_mem_test on cpu {}", linear_id);
+
+    let pattern = |i: usize| (linear_id << 56) | i;
+    let mut bufs: Vec<(usize, Layout, usize)> = Vec::new();
+    let mut objs: Vec<usize> = Vec::new();
+    for round in 0..100 {
+        for i in 0..64 {
+            let layout = Layout::from_size_align_unchecked(8 + ((i * 24 + round) % 512), 8);
+            let buf = alloc(layout) as *mut usize;
+            assert!(!buf.is_null(), "smp_mem_test out of memory");
+            *buf = pattern(i);
+            bufs.push((buf as usize, layout, pattern(i)));
+
+            let obj = FSP_SLAB.kmem_alloc(Layout::from_size_align_unchecked(8 << (i % 6), 8));
+            assert!(!obj.is_null(), "smp_mem_test out of slab objects");
+            *(obj as *mut usize) = pattern(i);
+            objs.push(obj as usize);
+        }
+
+        // Release every other buffer first so that the frees are interleaved with the
+        // allocations of the next round.
+        let mut i = 0;
+        bufs.retain(|&(buf, layout, value)| {
+            i += 1;
+            if i % 2 == 0 {
+                return true;
+            }
+            assert_eq!(*(buf as *const usize), value);
+            dealloc(buf as *mut u8, layout);
+            false
+        });
+        while let Some(obj) = objs.pop() {
+            let i = objs.len();
+            assert_eq!(*(obj as *const usize), pattern(i));
+            FSP_SLAB.kmem_dealloc(obj as *mut u8, Layout::from_size_align_unchecked(8 << (i % 6), 8));
+        }
+    }
+
+    for (buf, layout, value) in bufs {
+        assert_eq!(*(buf as *const usize), value);
+        dealloc(buf as *mut u8, layout);
+    }
+
+    debug!("smp_mem_test on cpu {} done", linear_id);
+}
+
+/// Allocates blocks of every order from FSP_PAGES, checks that they are naturally aligned and
+/// don't overlap, and frees them in a different order so that they have to be merged back.
+#[cfg(feature = "buddy")]
+fn page_test() {
+    use alloc::vec::Vec;
+
+    debug!("page_test");
+    let free_count = FSP_PAGES.free_count();
+    let mut blocks: Vec<(usize, usize)> = Vec::new();
+    for round in 0..4 {
+        for order in 0..=fsp_buddy::MAX_ORDER {
+            let order = (order + round) % (fsp_buddy::MAX_ORDER + 1);
+            let addr = FSP_PAGES.alloc_pages(order) as usize;
+            if addr == 0 {
+                continue;
+            }
+            let len = fsp_buddy::PAGE_SIZE << order;
+            assert_eq!(addr & (len - 1), 0);
+            for &(other, other_order) in blocks.iter() {
+                assert!(addr + len <= other || other + (fsp_buddy::PAGE_SIZE << other_order) <= addr);
+            }
+            unsafe {
+                *(addr as *mut usize) = addr;
+            }
+            blocks.push((addr, order));
+        }
+    }
+
+    let mut i = 0;
+    while !blocks.is_empty() {
+        i = (i + 7) % blocks.len();
+        let (addr, order) = blocks.swap_remove(i);
+        unsafe {
+            assert_eq!(*(addr as *const usize), addr);
+            FSP_PAGES.free_pages(addr as *mut u8, order);
+        }
+    }
+    assert_eq!(FSP_PAGES.free_count(), free_count);
+    debug!("page_test done");
 }
 
 /// This function is called on panic.
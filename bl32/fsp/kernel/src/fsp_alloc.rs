@@ -13,16 +13,89 @@
 //!     - Deallocating a block that overlaps with another already-allocated block
 //!     - Deallocating a free block
 //!     - Not modifying the data structure correctly
+//!
+//! All the state is protected by a spinlock, so the allocator can be used from all cores at once.
+//! The functions registered with bectl() are called without holding it.
+//!
+//! Each operation only asserts on the blocks it touches. validate() checks the whole heap, and
+//! with the heap_check feature, it runs after every operation.
+//!
+//! With the heap_scrub feature, a buffer is zeroed as it is released, so that secrets don't
+//! outlive it.
+//!
+//! With the heap_track feature, every allocated buffer also records when it was allocated and the
+//! tag set on its core at that time, so that report() can list the buffers allocated since a
+//! checkpoint() that are still live. tagged_allocs() tells whether a tag was used at all, so that
+//! the heap is only walked when there may be something to report.
 
 extern crate alloc; // need this due to #![no_std]---for regular Rust, it is by default.
 
 use crate::debug;
+#[cfg(feature = "heap_track")]
+use crate::qemu_constants::PLATFORM_CORE_COUNT;
+use crate::spinlock::SpinLock;
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::ptr::{null_mut, NonNull};
+#[cfg(feature = "heap_track")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /// Buffer allocation size quantum: all buffers allocated are a multiple of this size.  This
-/// MUST be a power of two.
-const SIZE_QUANT: usize = 4;
+/// MUST be a power of two, and at least the alignment of BHead since every header is placed on a
+/// SIZE_QUANT boundary (the target is built with +strict-align).
+const SIZE_QUANT: usize = 8;
+
+/// Size of the redzone between the header and the user part of every allocated buffer with the
+/// heap_redzone feature. Its first word holds the size that was requested for the buffer, and the
+/// rest of it, as well as everything after the user part, holds the guard pattern. Free buffers
+/// are filled with the poison pattern past their links. alloc() checks the poison of every free
+/// buffer it looks at while searching the free lists, so a write to a free buffer that no
+/// allocation comes across goes unnoticed until validate() walks the whole heap.
+#[cfg(feature = "heap_redzone")]
+const REDZONE: usize = 2 * core::mem::size_of::<usize>();
+#[cfg(not(feature = "heap_redzone"))]
+const REDZONE: usize = 0;
+const GUARD_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+/// With the heap_scrub feature, free buffers are filled with zeroes past their links instead (the
+/// poison pattern of heap_redzone takes precedence), so that nothing stored in a buffer can be
+/// read back through a later allocation.
+const SCRUB: bool = cfg!(feature = "heap_scrub");
+
+/// Sequence number of the last allocation, shared by every FspAlloc so that a checkpoint covers
+/// all of them.
+#[cfg(feature = "heap_track")]
+static ALLOC_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Tag recorded with the buffers allocated on each core. 0 means no tag.
+#[cfg(feature = "heap_track")]
+static mut ALLOC_TAGS: [usize; PLATFORM_CORE_COUNT] = [0; PLATFORM_CORE_COUNT];
+
+/// Number of buffers allocated on each core while a tag was set on it.
+#[cfg(feature = "heap_track")]
+static mut TAGGED_ALLOCS: [usize; PLATFORM_CORE_COUNT] = [0; PLATFORM_CORE_COUNT];
+
+/// Returns a checkpoint for report(): every buffer allocated after this call is newer than it.
+#[cfg(feature = "heap_track")]
+pub fn checkpoint() -> usize {
+    ALLOC_SEQ.load(Ordering::SeqCst)
+}
+
+/// Sets the tag recorded with the buffers allocated on the current core from now on, and returns
+/// the previous one.
+#[cfg(feature = "heap_track")]
+pub fn set_tag(tag: usize) -> usize {
+    let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+    unsafe { core::mem::replace(&mut ALLOC_TAGS[linear_id], tag) }
+}
+
+/// Returns the number of buffers allocated with a tag on the current core so far. If it hasn't
+/// changed since a tag was set, report() can't find any buffer with that tag.
+#[cfg(feature = "heap_track")]
+pub fn tagged_allocs() -> usize {
+    let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+    unsafe { TAGGED_ALLOCS[linear_id] }
+}
 
 /// End sentinel: value placed in bsize field of dummy block delimiting
 /// end of pool block. The most negative number which will fit in a
@@ -38,10 +111,17 @@ const ESENT: usize = usize::max_value();
 //    bufsize bsize;                  /* Buffer size: positive if free,
 //                                       negative if allocated. */
 //};
+// The headers are overlaid on raw memory and BHead/BFHead are both read from the same address, so
+// their layout must not be left to the compiler.
+#[repr(C)]
 struct BHead {
     prevfree: usize,
     bsize: usize,
     allocated: bool,
+    #[cfg(feature = "heap_track")]
+    seq: usize, // sequence number of the allocation, if allocated
+    #[cfg(feature = "heap_track")]
+    tag: usize, // tag of the core that allocated it, if allocated
 }
 
 impl BHead {
@@ -50,6 +130,10 @@ impl BHead {
             prevfree: 0,
             bsize: 0,
             allocated: false,
+            #[cfg(feature = "heap_track")]
+            seq: 0,
+            #[cfg(feature = "heap_track")]
+            tag: 0,
         }
     }
 
@@ -101,6 +185,7 @@ impl BHead {
 //    struct bfhead *flink;           /* Forward link */
 //    struct bfhead *blink;           /* Backward link */
 //};
+#[repr(C)]
 struct QLinks {
     flink: usize,
     blink: usize,
@@ -110,6 +195,7 @@ struct QLinks {
 //    struct bhead bh;                /* Common allocated/free header */
 //    struct qlinks ql;               /* Links on free list */
 //};
+#[repr(C)]
 struct BFHead {
     bh: BHead,
     ql: QLinks,
@@ -203,12 +289,99 @@ impl BFHead {
     }
 }
 
+/// Compaction function (BGET's compfcn). It is called with the size of a request that can't be
+/// satisfied and the number of times it has been called for that request (starting at 1), and
+/// returns true if it released some memory so that the allocation is worth retrying.
+pub type CompactFn = fn(usize, usize) -> bool;
+
+/// Acquisition function (BGET's acqfcn). It is asked for a new pool of the given length, and
+/// returns null if it has no more memory to give.
+pub type AcquireFn = fn(usize) -> *mut u8;
+
+/// Release function (BGET's relfcn). It is given back a pool of the given length that was
+/// obtained from the acquisition function, once every buffer in it has been released.
+pub type ReleaseFn = fn(*mut u8, usize);
+
+/// Heap statistics, as returned by FspAlloc::stats() (BGET's bstats()). Sizes are in bytes and
+/// include the block headers.
+#[derive(Clone, Copy)]
+pub struct FspAllocStats {
+    pub cur_alloc: usize, // currently allocated
+    pub max_alloc: usize, // high-water mark of cur_alloc
+    pub tot_free: usize,  // total free
+    pub max_free: usize,  // largest free block
+    pub num_free: usize,  // number of free blocks
+    pub num_get: usize,   // number of allocations so far
+    pub num_rel: usize,   // number of deallocations so far
+}
+
+/// Why try_alloc() failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    OutOfMemory,    // no free block is big enough, even after compaction and acquisition
+    BadLayout,      // the layout can't be allocated, e.g., it has a size of 0
+    NotInitialized, // init() hasn't been called yet
+}
+
+/// Header at the start of every pool given to the allocator. BGET itself doesn't remember its
+/// pools, but we chain them together so that the whole heap can be walked. The first block of a
+/// pool follows this header, and the end sentinel is at the very end of the pool.
+#[repr(C)]
+struct BPool {
+    next: usize,      // next pool, 0 if this is the last one
+    len: usize,       // length of the pool, including this header and the end sentinel
+    acquired: bool,   // whether the pool was obtained from the acquisition function
+    orig_addr: usize, // address of the memory given for the pool, before aligning it
+    orig_len: usize,  // length of the memory given for the pool, before aligning it
+}
+
+impl BPool {
+    fn from_addr(addr: usize) -> &'static mut BPool {
+        unsafe { &mut *(addr as *mut BPool) }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const BPool as usize
+    }
+
+    /// Returns the address of the first block of the pool.
+    fn first_block(&self) -> usize {
+        self.addr() + core::mem::size_of::<BPool>()
+    }
+}
+
+/// Number of free lists. The default policy is BGET's: a single free list scanned first-fit. With
+/// the segregated_fit feature, free list i only holds free blocks whose size is in
+/// [2^i, 2^(i+1)), so that a request can skip every list whose blocks are all too small, and any
+/// block of a list above the one the request size falls into is big enough.
+#[cfg(not(feature = "segregated_fit"))]
+const NUM_BINS: usize = 1;
+#[cfg(feature = "segregated_fit")]
+const NUM_BINS: usize = core::mem::size_of::<usize>() * 8;
+
 //static struct bfhead freelist = {     /* List of free buffers */
 //    {0, 0},
 //    {&freelist, &freelist}
 //};
 pub struct FspAlloc {
-    freelist: BFHead,
+    heap: SpinLock<FspHeap>,
+}
+
+/// The state of an FspAlloc, which is only reached through its lock. The free lists start at
+/// sentinels in here that the free buffers link to, so it must not move once init() is called.
+struct FspHeap {
+    freelists: [BFHead; NUM_BINS],
+    binmap: usize, // bit i is set if freelists[i] is not empty
+    pools: usize,  // first pool in the chain of pools, 0 if there is none
+    compfcn: Option<CompactFn>,
+    acqfcn: Option<AcquireFn>,
+    relfcn: Option<ReleaseFn>,
+    exp_incr: usize, // automatic pool size for pools obtained from acqfcn
+    totalloc: usize, // bytes currently allocated
+    maxalloc: usize, // high-water mark of totalloc
+    numget: usize,   // number of allocations
+    numrel: usize,   // number of deallocations
+    initialized: bool,
 }
 
 impl FspAlloc {
@@ -219,10 +392,262 @@ impl FspAlloc {
     // the instance.
     pub const fn new() -> FspAlloc {
         FspAlloc {
-            freelist: BFHead::new(),
+            heap: SpinLock::new(FspHeap {
+                freelists: [BFHead::new(); NUM_BINS],
+                binmap: 0,
+                pools: 0,
+                compfcn: None,
+                acqfcn: None,
+                relfcn: None,
+                exp_incr: 0,
+                totalloc: 0,
+                maxalloc: 0,
+                numget: 0,
+                numrel: 0,
+                initialized: false,
+            }),
+        }
+    }
+
+    /// Initializes the allocator with its first pool of memory. This must be called before using
+    /// the allocator, and only once; more memory can be added later with add_region().
+    /// &self is used instead of &mut self since a static struct cannot call a method with a
+    /// mutable self.
+    pub fn init(&self, buf: usize, len: usize) {
+        let mut heap = self.heap.lock();
+        for freelist in heap.freelists.iter() {
+            freelist.init(0, 0, freelist, freelist);
+        }
+        heap.binmap = 0;
+        heap.pools = 0;
+
+        heap.add_pool(buf, len, false);
+        heap.initialized = true;
+    }
+
+    /// Allocates like GlobalAlloc::alloc(), but says why it failed instead of returning null.
+    /// Unlike the global allocator, whose failures end up in alloc_error_handler, this lets the
+    /// caller recover, e.g., by failing an SMC with an error code.
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.can_alloc(&layout)?;
+        NonNull::new(unsafe { self.alloc_quiet(layout) }).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Says why try_alloc() would fail for `layout` whatever the state of the pools, if it would.
+    pub fn can_alloc(&self, layout: &Layout) -> Result<(), AllocError> {
+        if !self.heap.lock().initialized {
+            return Err(AllocError::NotInitialized);
+        }
+        // GlobalAlloc::alloc() doesn't allow zero-sized layouts.
+        if layout.size() == 0 {
+            return Err(AllocError::BadLayout);
+        }
+        Ok(())
+    }
+
+    /// Allocates like alloc(), but only from the pools as they are: the functions registered with
+    /// bectl() aren't called, and a failure isn't reported on the console. This is for a caller
+    /// that has somewhere else to go when the pools are exhausted.
+    pub unsafe fn alloc_from_pools(&self, layout: Layout) -> *mut u8 {
+        self.heap.lock().get(&layout)
+    }
+
+    /// Allocates like alloc(), calling the functions registered with bectl(), but without
+    /// reporting a failure on the console. For try_alloc() and the arenas, a failure is an
+    /// expected outcome that their caller handles.
+    pub unsafe fn alloc_quiet(&self, layout: Layout) -> *mut u8 {
+        let mut buf = self.heap.lock().get(&layout);
+
+        // The pools are exhausted. If a compaction function was registered,
+        // keep calling it for as long as it says that it released
+        // something, retrying the allocation each time.
+        if buf.is_null() {
+            let compfcn = self.heap.lock().compfcn;
+            if let Some(compact) = compfcn {
+                let mut seq = 0;
+                while buf.is_null() {
+                    seq += 1;
+                    if !compact(layout.size(), seq) {
+                        break;
+                    }
+                    buf = self.heap.lock().get(&layout);
+                }
+            }
+        }
+
+        // Still nothing. If an acquisition function was registered, ask it
+        // for a new pool, of the automatic pool size or, if the request
+        // can't fit in a pool of that size, just big enough for the request.
+        if buf.is_null() {
+            let (acqfcn, len) = {
+                let heap = self.heap.lock();
+                (heap.acqfcn, heap.pool_len(&layout))
+            };
+            if let Some(acquire) = acqfcn {
+                let pool = acquire(len);
+                if !pool.is_null() {
+                    let mut heap = self.heap.lock();
+                    heap.add_pool(pool as usize, len, true);
+                    buf = heap.get(&layout);
+                }
+            }
+        }
+
+        buf
+    }
+
+    /// This is bstats(). The free space figures are gathered by walking the free lists.
+    pub fn stats(&self) -> FspAllocStats {
+        let heap = self.heap.lock();
+        let mut stats = FspAllocStats {
+            cur_alloc: heap.totalloc,
+            max_alloc: heap.maxalloc,
+            tot_free: 0,
+            max_free: 0,
+            num_free: 0,
+            num_get: heap.numget,
+            num_rel: heap.numrel,
+        };
+
+        for freelist in heap.freelists.iter() {
+            let mut b = freelist.flink_ref();
+            while !b.eq(freelist) {
+                stats.tot_free += b.bsize();
+                if b.bsize() > stats.max_free {
+                    stats.max_free = b.bsize();
+                }
+                stats.num_free += 1;
+                b = b.flink_ref();
+            }
+        }
+
+        stats
+    }
+
+    /// This is bpoolv(), extended to every pool and to the free lists. It walks every pool from its
+    /// first block to its end sentinel and checks the boundary tags of every block, the back
+    /// links to free blocks, that no two free blocks are adjacent, and that the free lists hold
+    /// exactly the free blocks found in memory. Returns false, after reporting the problem on the
+    /// console, if the heap is inconsistent.
+    pub fn validate(&self) -> bool {
+        self.heap.lock().consistent()
+    }
+
+    /// Reports every live buffer allocated after `checkpoint` on the console, with its address,
+    /// size, sequence number and tag. If `tag` is given, only the buffers allocated with that tag
+    /// are reported. Returns the number of buffers reported.
+    #[cfg(feature = "heap_track")]
+    pub fn report(&self, checkpoint: usize, tag: Option<usize>) -> usize {
+        let heap = self.heap.lock();
+        let hsize = core::mem::size_of::<BHead>();
+        let mut count: usize = 0;
+
+        let mut ptr: usize = heap.pools;
+        while ptr != 0 {
+            let pool = BPool::from_addr(ptr);
+            let end = pool.addr() + pool.len - hsize;
+            let mut addr = pool.first_block();
+            while addr != end {
+                let b = BHead::from_addr(addr);
+                if b.is_allocated() && b.seq > checkpoint && tag.map_or(true, |tag| b.tag == tag) {
+                    debug!(
+                        "live buffer at {} size {} seq {} tag {:#x}",
+                        addr + heap.head_size(),
+                        b.bsize() - heap.head_size() - REDZONE,
+                        b.seq,
+                        b.tag
+                    );
+                    count += 1;
+                }
+                addr += b.bsize();
+            }
+            ptr = pool.next;
+        }
+
+        count
+    }
+
+    /// This is bectl(). It registers the functions that alloc() falls back on when the pools are
+    /// exhausted: `compact` is given a chance to release memory first, then `acquire` is asked for
+    /// a new pool of `pool_incr` bytes (or more, if the request wouldn't fit in a pool of that
+    /// size). Once all the buffers of an acquired pool are released, the pool is handed to
+    /// `release`. Any of the functions can be None.
+    pub fn bectl(
+        &self,
+        compact: Option<CompactFn>,
+        acquire: Option<AcquireFn>,
+        release: Option<ReleaseFn>,
+        pool_incr: usize,
+    ) {
+        let mut heap = self.heap.lock();
+        heap.compfcn = compact;
+        heap.acqfcn = acquire;
+        heap.relfcn = release;
+        heap.exp_incr = pool_incr;
+    }
+
+    /// This is bpool(): it adds the memory in [buf, buf + len) to the allocator as a pool of its
+    /// own. It can be called at any time after init(), and the memory must not overlap any pool
+    /// that was added before.
+    pub fn add_region(&self, buf: usize, len: usize) {
+        self.heap.lock().add_pool(buf, len, false);
+    }
+
+    /// Resizes a buffer in place if the boundary tags allow it: a buffer grows into the next
+    /// buffer in memory if that one is free and big enough, and shrinks by giving its tail back
+    /// to the free list. Returns false, leaving the buffer as it was, if it can't grow in place.
+    pub unsafe fn resize(&self, buf: *mut u8, new_size: usize) -> bool {
+        assert!(!buf.is_null(), "FspAlloc.resize() resizing a null buffer");
+
+        let mut heap = self.heap.lock();
+        let b: &mut BHead = BHead::from_addr((buf as usize) - heap.head_size());
+        assert!(b.is_allocated(), "FspAlloc.resize() resizing a free buffer");
+        assert!(
+            heap.guards_intact(b),
+            "FspAlloc.resize() redzone overwritten"
+        );
+        let size = heap.buf_size(new_size);
+
+        if size > b.bsize() {
+            let bn: &mut BFHead = BFHead::from_addr(b.addr() + b.bsize());
+            if !bn.is_allocated() && b.bsize() + bn.bsize() >= size {
+                // The next buffer is free and big enough. Take it off the free
+                // list and absorb it; whatever we don't need is given back by
+                // trim() below.
+                let bnn: &mut BHead = BHead::from_addr(bn.addr() + bn.bsize());
+                assert!(
+                    bnn.prevfree() == bn.bsize(),
+                    "FspAlloc.resize() inconsistent buffer size information"
+                );
+                assert!(
+                    heap.poison_intact(
+                        bn,
+                        bn.addr() + core::mem::size_of::<BFHead>(),
+                        bn.addr() + bn.bsize()
+                    ),
+                    "FspAlloc.resize() free buffer written after release"
+                );
+                heap.unlink(bn);
+                heap.count_alloc(bn.bsize());
+                b.set_bsize(b.bsize() + bn.bsize());
+                // The buffer after the absorbed one is now preceded by an
+                // allocated buffer.
+                bnn.set_prevfree(0);
+            }
+        }
+
+        if size <= b.bsize() {
+            heap.trim(b, size);
+            heap.set_guards(b, new_size);
+            heap.check();
+            return true;
         }
+
+        false
     }
+}
 
+impl FspHeap {
     fn ql_size(&self) -> usize {
         core::mem::size_of::<QLinks>()
     }
@@ -236,21 +661,341 @@ impl FspAlloc {
         }
     }
 
-    /// This is bpool(), but renamed to init(). Unlike the original bpool(), we're assuming that
-    /// this is only called once in the beginning. This must be called.
-    /// &self is used instead of &mut self since a static struct cannot call a method with a
-    /// mutable self.
-    pub fn init(&self, buf: usize, len: usize) {
-        self.freelist.init(0, 0, &self.freelist, &self.freelist);
-        let len = len & !(SIZE_QUANT - 1);
+    /// Returns the index of the free list that a free block of `bsize` bytes belongs to.
+    #[cfg(not(feature = "segregated_fit"))]
+    fn bin_of(&self, _bsize: usize) -> usize {
+        0
+    }
+
+    /// Returns the index of the free list that a free block of `bsize` bytes belongs to.
+    #[cfg(feature = "segregated_fit")]
+    fn bin_of(&self, bsize: usize) -> usize {
+        (core::mem::size_of::<usize>() * 8 - 1) - bsize.leading_zeros() as usize
+    }
+
+    /// Returns the index of the first non-empty free list at or after `bin`, if any.
+    fn next_bin(&self, bin: usize) -> Option<usize> {
+        if bin >= NUM_BINS {
+            return None;
+        }
+        let map = self.binmap & !((1 << bin) - 1);
+        if map == 0 {
+            None
+        } else {
+            Some(map.trailing_zeros() as usize)
+        }
+    }
+
+    /// Chains the free block `b` to the end of the free list for its size.
+    fn link(&mut self, b: &BFHead) {
+        let bin = self.bin_of(b.bsize());
+        let freelist = &self.freelists[bin];
+        assert!(
+            freelist.blink_ref().flink_ref().eq(freelist),
+            "FspAlloc.link() inconsistent list formation"
+        );
+        assert!(
+            freelist.flink_ref().blink_ref().eq(freelist),
+            "FspAlloc.link() inconsistent list formation"
+        );
+        b.set_flink(freelist);
+        b.set_blink(freelist.blink_ref());
+        freelist.set_blink(b);
+        b.blink_mut_ref().set_flink(b);
+        self.binmap |= 1 << bin;
+    }
+
+    /// Removes the free block `b` from its free list. This must be called before the size of `b`
+    /// changes, since the size tells which list it is on.
+    fn unlink(&mut self, b: &BFHead) {
+        assert!(
+            b.blink_ref().flink_ref().eq(b),
+            "FspAlloc.unlink() inconsistent list formation"
+        );
+        assert!(
+            b.flink_ref().blink_ref().eq(b),
+            "FspAlloc.unlink() inconsistent list formation"
+        );
+        b.blink_mut_ref().set_flink(b.flink_ref());
+        b.flink_mut_ref().set_blink(b.blink_ref());
+
+        let bin = self.bin_of(b.bsize());
+        let freelist = &self.freelists[bin];
+        if freelist.flink_ref().eq(freelist) {
+            self.binmap &= !(1 << bin);
+        }
+    }
+
+    /// Changes the size of the free block `b`, moving it to another free list if needed.
+    fn resize_free(&mut self, b: &BFHead, bsize: usize) {
+        if self.bin_of(b.bsize()) == self.bin_of(bsize) {
+            b.set_bsize(bsize);
+        } else {
+            self.unlink(b);
+            b.set_bsize(bsize);
+            self.link(b);
+        }
+    }
+
+    /// This is validate() for when the lock is already held.
+    fn consistent(&self) -> bool {
+        let hsize = core::mem::size_of::<BHead>();
+        let mut nfree: usize = 0;
+        let mut free_bytes: usize = 0;
+        let mut alloc_bytes: usize = 0;
+
+        let mut ptr: usize = self.pools;
+        while ptr != 0 {
+            let pool = BPool::from_addr(ptr);
+            let end = pool.addr() + pool.len - hsize; // the end sentinel
+            let mut addr = pool.first_block();
+            let mut prevfree: usize = 0; // what the back link of the block at addr should be
+
+            while addr != end {
+                let b = BHead::from_addr(addr);
+                if b.bsize() < core::mem::size_of::<BFHead>()
+                    || b.bsize() & (SIZE_QUANT - 1) != 0
+                    || b.bsize() > end - addr
+                {
+                    return self.invalid("bad buffer size", addr);
+                }
+                if b.prevfree() != prevfree {
+                    return self.invalid("bad back link", addr);
+                }
+                if b.is_allocated() {
+                    if !self.guards_intact(b) {
+                        return self.invalid("overwritten redzone", addr);
+                    }
+                    alloc_bytes += b.bsize();
+                    prevfree = 0;
+                } else {
+                    if prevfree != 0 {
+                        return self.invalid("two adjacent free buffers", addr);
+                    }
+                    let bf = BFHead::from_addr(addr);
+                    if !self.poison_intact(
+                        bf,
+                        addr + core::mem::size_of::<BFHead>(),
+                        addr + b.bsize(),
+                    ) {
+                        return self.invalid("overwritten free buffer", addr);
+                    }
+                    nfree += 1;
+                    free_bytes += b.bsize();
+                    prevfree = b.bsize();
+                }
+                addr += b.bsize();
+            }
+
+            let bn = BHead::from_addr(end);
+            if bn.bsize() != ESENT || !bn.is_allocated() || bn.prevfree() != prevfree {
+                return self.invalid("bad end sentinel", end);
+            }
+
+            ptr = pool.next;
+        }
+
+        let mut nlisted: usize = 0;
+        let mut listed_bytes: usize = 0;
+        for (bin, freelist) in self.freelists.iter().enumerate() {
+            let mut b = freelist.flink_ref();
+            if ((self.binmap >> bin) & 1 != 0) == b.eq(freelist) {
+                return self.invalid("bad free list map", freelist.addr());
+            }
+            while !b.eq(freelist) {
+                if !b.flink_ref().blink_ref().eq(b) || !b.blink_ref().flink_ref().eq(b) {
+                    return self.invalid("bad free list links", b.addr());
+                }
+                if b.is_allocated() {
+                    return self.invalid("allocated buffer on a free list", b.addr());
+                }
+                if self.bin_of(b.bsize()) != bin {
+                    return self.invalid("free buffer on the wrong free list", b.addr());
+                }
+                if BHead::from_addr(b.addr() + b.bsize()).prevfree() != b.bsize() {
+                    return self.invalid("free list entry is not a free buffer", b.addr());
+                }
+                nlisted += 1;
+                listed_bytes += b.bsize();
+                // This also stops us from going around a corrupted list forever.
+                if nlisted > nfree {
+                    return self.invalid("more free list entries than free buffers", b.addr());
+                }
+                b = b.flink_ref();
+            }
+        }
+
+        if nlisted != nfree || listed_bytes != free_bytes {
+            return self.invalid("free lists don't match the free buffers", self.pools);
+        }
+        if alloc_bytes != self.totalloc {
+            return self.invalid("allocated byte count doesn't match the heap", self.pools);
+        }
+
+        true
+    }
+
+    fn invalid(&self, what: &str, addr: usize) -> bool {
+        debug!("FspAlloc.validate() {} at {}", what, addr);
+        false
+    }
+
+    /// With the heap_check feature, validates the whole heap. This is called at the end of every
+    /// operation.
+    fn check(&self) {
+        #[cfg(feature = "heap_check")]
+        assert!(self.consistent(), "FspAlloc.check() inconsistent heap");
+    }
+
+    /// Returns the distance from the header of a buffer to its user part.
+    fn head_size(&self) -> usize {
+        core::mem::size_of::<BHead>() + REDZONE
+    }
+
+    /// With the heap_redzone feature, records the requested `size` of the allocated buffer `b` at
+    /// the start of its redzone and fills the rest of the redzone and everything after the user
+    /// part with the guard pattern.
+    fn set_guards(&self, b: &BHead, size: usize) {
+        if REDZONE == 0 {
+            return;
+        }
+        let front = b.addr() + core::mem::size_of::<BHead>();
+        let buf = b.addr() + self.head_size();
+        unsafe {
+            *(front as *mut usize) = size;
+        }
+        self.fill(front + core::mem::size_of::<usize>(), buf, GUARD_BYTE);
+        self.fill(buf + size, b.addr() + b.bsize(), GUARD_BYTE);
+    }
+
+    /// With the heap_redzone feature, checks the guard patterns around the user part of the
+    /// allocated buffer `b`, reporting the buffer on the console if they were overwritten.
+    fn guards_intact(&self, b: &BHead) -> bool {
+        if REDZONE == 0 {
+            return true;
+        }
+        let front = b.addr() + core::mem::size_of::<BHead>();
+        let buf = b.addr() + self.head_size();
+        let size = unsafe { *(front as *const usize) };
+        if size > b.bsize() - self.head_size()
+            || !self.filled(front + core::mem::size_of::<usize>(), buf, GUARD_BYTE)
+            || !self.filled(buf + size, b.addr() + b.bsize(), GUARD_BYTE)
+        {
+            debug!(
+                "FspAlloc: redzone of the buffer at {} of size {} overwritten",
+                buf, size
+            );
+            return false;
+        }
+        true
+    }
+
+    /// With the heap_redzone feature, fills [start, end) of a free buffer with the poison pattern.
+    /// With the heap_scrub feature alone, fills it with zeroes.
+    fn poison(&self, start: usize, end: usize) {
+        if REDZONE != 0 {
+            self.fill(start, end, POISON_BYTE);
+        } else if SCRUB {
+            self.fill(start, end, 0);
+        }
+    }
+
+    /// With the heap_redzone feature, checks that [start, end) of the free buffer `b` still holds
+    /// the poison pattern, reporting the buffer on the console if it doesn't.
+    fn poison_intact(&self, b: &BFHead, start: usize, end: usize) -> bool {
+        if REDZONE == 0 {
+            return true;
+        }
+        if !self.filled(start, end, POISON_BYTE) {
+            debug!(
+                "FspAlloc: free buffer at {} of size {} written after release",
+                b.addr(),
+                b.bsize()
+            );
+            return false;
+        }
+        true
+    }
+
+    fn fill(&self, start: usize, end: usize, byte: u8) {
+        unsafe {
+            core::ptr::write_bytes(start as *mut u8, byte, end - start);
+        }
+    }
+
+    fn filled(&self, start: usize, end: usize, byte: u8) -> bool {
+        let mut addr = start;
+        while addr < end {
+            if unsafe { *(addr as *const u8) } != byte {
+                return false;
+            }
+            addr += 1;
+        }
+        true
+    }
 
-        // Since the block is initially occupied by a single free buffer,
-        // it had better not be (much) larger than the largest buffer
-        // whose size we can store in bhead.bsize.
+    /// Accounts for `size` more bytes being allocated.
+    fn count_alloc(&mut self, size: usize) {
+        self.totalloc += size;
+        if self.totalloc > self.maxalloc {
+            self.maxalloc = self.totalloc;
+        }
+    }
+
+    /// Returns the number of bytes of a pool that can never be allocated: the pool header and the
+    /// end sentinel.
+    fn pool_overhead(&self) -> usize {
+        core::mem::size_of::<BPool>() + core::mem::size_of::<BHead>()
+    }
+
+    /// Returns the length that a pool must have, once aligned, for its first block to be at least
+    /// `block` bytes. add_pool() doesn't take a pool without room for a free buffer of SIZE_QUANT
+    /// bytes, so this is never less than for that.
+    fn pool_size_for(&self, block: usize) -> usize {
+        let min_block = core::mem::size_of::<BFHead>() + SIZE_QUANT;
+        self.pool_overhead() + if block > min_block { block } else { min_block }
+    }
+
+    /// Returns the length of the pool to acquire for a request for `layout`.
+    fn pool_len(&self, layout: &Layout) -> usize {
+        let mut block = self.buf_size(layout.size());
+        let align = self.buf_align(layout.align());
+        if align > SIZE_QUANT {
+            // fit() moves the buffer down by another `align` if the padding in front of it
+            // is too small to be a free block, so leave room for such a block.
+            block +=
+                (align - SIZE_QUANT) + self.size_q() + core::mem::size_of::<BFHead>() + SIZE_QUANT;
+        }
+        // add_pool() may have to skip up to SIZE_QUANT - 1 bytes to align the pool.
+        let mut len = self.pool_size_for(block) + (SIZE_QUANT - 1);
+        if len < self.exp_incr {
+            len = self.exp_incr;
+        }
+        len
+    }
+
+    fn add_pool(&mut self, buf: usize, len: usize, acquired: bool) {
+        // Every block header has to start on a SIZE_QUANT boundary.
+        let pad = ((buf + (SIZE_QUANT - 1)) & !(SIZE_QUANT - 1)) - buf;
         assert!(
-            (len - core::mem::size_of::<BFHead>()) <= ESENT,
-            "FspAlloc.init() len too big"
+            len > pad && (len - pad) >= self.pool_size_for(0),
+            "FspAlloc.add_region() len too small"
         );
+        let orig_addr = buf;
+        let orig_len = len;
+        let buf = buf + pad;
+        let len = (len - pad) & !(SIZE_QUANT - 1);
+
+        // Chain the pool to the list of pools.
+        let pool: &mut BPool = BPool::from_addr(buf);
+        pool.len = len;
+        pool.acquired = acquired;
+        pool.orig_addr = orig_addr;
+        pool.orig_len = orig_len;
+        pool.next = self.pools;
+        self.pools = pool.addr();
+        let buf = pool.first_block();
+        let len = len - core::mem::size_of::<BPool>();
 
         let b: &mut BFHead = BFHead::from_addr(buf);
 
@@ -259,20 +1004,6 @@ impl FspAlloc {
         // recombination when the first block in memory is released.
         b.set_prevfree(0);
 
-        // Chain the new block to the free list.
-        assert!(
-            self.freelist.blink_ref().flink_ref().eq(&self.freelist),
-            "FspAlloc.init() incorrect list initialization"
-        );
-        assert!(
-            self.freelist.flink_ref().blink_ref().eq(&self.freelist),
-            "FspAlloc.init() incorrect list initialization"
-        );
-        b.set_flink(&self.freelist);
-        b.set_blink(self.freelist.blink_ref());
-        self.freelist.set_blink(b);
-        b.blink_mut_ref().set_flink(b);
-
         // Create a dummy allocated buffer at the end of the pool. This dummy
         // buffer is seen when a buffer at the end of the pool is released and
         // blocks recombination of the last buffer with the dummy buffer at
@@ -284,54 +1015,154 @@ impl FspAlloc {
         let len = len - core::mem::size_of::<BHead>();
         b.set_bsize(len);
         b.set_allocated(false);
+        self.poison(buf + core::mem::size_of::<BFHead>(), buf + len);
+
+        // Chain the new block to the free list.
+        self.link(b);
 
         let bn: &mut BHead = BHead::from_addr(buf + len);
         bn.set_prevfree(len);
         bn.set_bsize(ESENT);
         bn.set_allocated(true);
+
+        self.check();
     }
-}
 
-unsafe impl GlobalAlloc for FspAlloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut size: usize = layout.size();
-        // Need at least room for the queue links.
-        if size < self.size_q() {
-            size = self.size_q();
+    /// Takes an acquired pool that has become entirely free out of the allocator, if there is a
+    /// release function to give it back to. `b` is the single free block that spans the pool.
+    /// Returns the address and the length of the pool to release, as the acquisition function gave
+    /// them.
+    fn unchain_pool(&mut self, b: &BFHead) -> Option<(usize, usize)> {
+        if self.relfcn.is_none() {
+            return None;
         }
-        size = (size + (SIZE_QUANT - 1)) & (!(SIZE_QUANT - 1));
-        size = size + core::mem::size_of::<BHead>();
 
-        // Scan the free list searching for the first buffer big enough
-        // to hold the requested size buffer.
+        let mut prev: usize = 0;
+        let mut ptr: usize = self.pools;
+        while ptr != 0 {
+            let pool = BPool::from_addr(ptr);
+            if pool.first_block() == b.addr() {
+                if !pool.acquired {
+                    return None;
+                }
+                self.unlink(b);
+                if prev == 0 {
+                    self.pools = pool.next;
+                } else {
+                    BPool::from_addr(prev).next = pool.next;
+                }
+                return Some((pool.orig_addr, pool.orig_len));
+            }
+            prev = ptr;
+            ptr = pool.next;
+        }
+
+        None
+    }
+
+    /// Finds where a buffer of `size` bytes (header included) whose user part is aligned to
+    /// `align` can be carved out of the free block `b`. As in BGET, the buffer is taken from the
+    /// end of `b`, as close to the end as the alignment permits, so that the leading padding stays
+    /// on the free list as what is left of `b`. That leading part either has to
+    /// disappear entirely or be big enough to be a free block on its own.
+    ///
+    /// Returns the address of the header of the buffer, or None if it doesn't fit.
+    fn fit(&self, b: &BFHead, size: usize, align: usize) -> Option<usize> {
+        let hsize = self.head_size();
+        if b.bsize() < size {
+            return None;
+        }
+
+        let mut buf = (b.addr() + b.bsize() - size + hsize) & !(align - 1);
+        while buf >= b.addr() + hsize {
+            let lead = buf - hsize - b.addr();
+            if lead == 0 || lead > (self.size_q() + core::mem::size_of::<BFHead>()) {
+                return Some(buf - hsize);
+            }
+            buf -= align;
+        }
+
+        None
+    }
+
+    /// Searches the free lists for a buffer big enough to hold a buffer of `size` bytes (header
+    /// included) at the requested alignment, and allocates it. Returns null if there is none.
+    ///
+    /// The first buffer on the list the requested size belongs to is tried first, so that a buffer
+    /// that was just released is reused by the next request of the same size instead of a bigger
+    /// one being split. Then the non-empty lists above it are tried, from the smallest one up. Any
+    /// buffer on them is big enough, so unless the alignment gets in the way, the first one is
+    /// taken and the search costs a look at the bitmap. The rest of the list of the requested size,
+    /// which holds buffers both smaller and bigger than the request, is only searched, first-fit,
+    /// when nothing above it fits. The worst case is thus still a linear scan of that one list.
+    /// Without the segregated_fit feature, there is only that list, as in BGET.
+    unsafe fn scan(&mut self, size: usize, align: usize) -> *mut u8 {
+        let bin = self.bin_of(size);
+        let buf = self.scan_bin(bin, size, align, 1);
+        if !buf.is_null() {
+            return buf;
+        }
+
+        let mut next = bin + 1;
+        while let Some(higher) = self.next_bin(next) {
+            let buf = self.scan_bin(higher, size, align, usize::MAX);
+            if !buf.is_null() {
+                return buf;
+            }
+            next = higher + 1;
+        }
 
-        let mut b = self.freelist.flink_mut_ref();
+        self.scan_bin(bin, size, align, usize::MAX)
+    }
 
-        while !b.eq(&self.freelist) {
+    /// Searches the first `max` buffers of the free list `bin` first-fit for a buffer that can
+    /// hold a buffer of `size` bytes (header included) at the requested alignment, and allocates
+    /// it. Returns null if there is none.
+    unsafe fn scan_bin(&mut self, bin: usize, size: usize, align: usize, max: usize) -> *mut u8 {
+        // The buffers on the list are linked to its sentinel by address, so they are reached the
+        // same way as in put(), rather than through a borrow of self that would prevent taking one
+        // off the list.
+        let freelist = BFHead::from_addr(self.freelists[bin].addr());
+        let mut b = freelist.flink_mut_ref();
+        let mut seen = 0;
+
+        while !b.eq(freelist) && seen < max {
             assert!(
                 !b.is_allocated(),
                 "FspAlloc.alloc() attempting to allocate an already-allocated buffer"
             );
             let bsize = b.bsize();
-            if bsize >= size {
+            // No free buffer that the scan comes across may have been
+            // written since it was released, whether it is taken or not.
+            assert!(
+                self.poison_intact(
+                    b,
+                    b.addr() + core::mem::size_of::<BFHead>(),
+                    b.addr() + bsize
+                ),
+                "FspAlloc.alloc() free buffer written after release"
+            );
+            if let Some(addr) = self.fit(b, size, align) {
                 // Buffer is big enough to satisfy the request. Allocate it
-                // to the caller. We must decide whether the buffer is large
-                // enough to split into the part given to the caller and a
-                // free buffer that remains on the free list, or whether the
-                // entire buffer should be removed from the free list and
-                // given to the caller in its entirety. We only split the
-                // buffer if enough room remains for a header plus the minimum
-                // quantum of allocation.
-                if (bsize - size) > (self.size_q() + core::mem::size_of::<BFHead>()) {
-                    let ba: &mut BHead = BHead::from_addr(b.addr() + bsize - size);
-                    let bn: &mut BHead = BHead::from_addr(ba.addr() + size);
+                // to the caller. fit() has already decided whether the buffer
+                // is split into a leading part that remains on the free list
+                // and the part given to the caller (which always extends to
+                // the end of the free buffer), or whether the entire buffer
+                // should be removed from the free list and given to the
+                // caller in its entirety. We only split the buffer if enough
+                // room remains for a header plus the minimum quantum of
+                // allocation.
+                if addr != b.addr() {
+                    let ba: &mut BHead = BHead::from_addr(addr);
+                    let bn: &mut BHead = BHead::from_addr(b.addr() + bsize);
                     assert!(
                         bn.prevfree() == b.bsize(),
                         "FspAlloc.alloc() inconsistent buffer size information"
                     );
                     // Subtract size from length of free block.
-                    let bsize = bsize - size;
-                    b.set_bsize(bsize);
+                    let bsize = addr - b.addr();
+                    let size = b.bsize() - bsize;
+                    self.resize_free(b, bsize);
                     // Link allocated buffer to the previous free buffer.
                     ba.set_prevfree(bsize);
                     // Plug negative size into user buffer.
@@ -340,7 +1171,7 @@ unsafe impl GlobalAlloc for FspAlloc {
                     // Mark buffer after this one not preceded by free block.
                     bn.set_prevfree(0);
 
-                    return (ba.addr() + core::mem::size_of::<BHead>()) as *mut u8;
+                    return (ba.addr() + self.head_size()) as *mut u8;
                 } else {
                     // The buffer isn't big enough to split. Give the whole
                     // shebang to the caller and remove it from the free list.
@@ -349,57 +1180,98 @@ unsafe impl GlobalAlloc for FspAlloc {
                         ba.prevfree() == b.bsize(),
                         "FspAlloc.alloc() inconsistent buffer size information"
                     );
-                    assert!(
-                        b.blink_ref().flink_ref().eq(b),
-                        "FspAlloc.alloc() inconsistent list formation"
-                    );
-                    assert!(
-                        b.flink_ref().blink_ref().eq(b),
-                        "FspAlloc.alloc() inconsistent list formation"
-                    );
 
-                    b.blink_mut_ref().set_flink(b.flink_ref());
-                    b.flink_mut_ref().set_blink(b.blink_ref());
+                    self.unlink(b);
                     // Negate size to mark buffer allocated.
                     b.set_bsize(b.bsize());
                     b.set_allocated(true);
                     // Zero the back pointer in the next buffer in memory
                     // to indicate that this buffer is allocated.
                     ba.set_prevfree(0);
-                    return (b.addr() + core::mem::size_of::<BHead>()) as *mut u8;
+                    return (b.addr() + self.head_size()) as *mut u8;
                 }
             }
 
             b = b.flink_mut_ref(); // Link to next buffer
+            seen += 1;
         }
 
-        // BECtl not implemented
+        null_mut()
+    }
 
-        debug!("No memory left to allocate");
+    /// Returns the buffer size (header included) that alloc() uses for a request of `size` bytes.
+    fn buf_size(&self, size: usize) -> usize {
+        let mut size = size;
+        // Need at least room for the queue links.
+        if size < self.size_q() {
+            size = self.size_q();
+        }
+        size = (size + (SIZE_QUANT - 1)) & (!(SIZE_QUANT - 1));
+        // The redzone after the user part is at least as big as the one before it.
+        size + self.head_size() + REDZONE
+    }
 
-        null_mut()
+    /// Returns the alignment that alloc() uses for a request aligned to `align`.
+    fn buf_align(&self, align: usize) -> usize {
+        // Every header sits on a SIZE_QUANT boundary and is itself a multiple of SIZE_QUANT, so
+        // smaller alignments are satisfied without doing anything.
+        if align > SIZE_QUANT {
+            align
+        } else {
+            SIZE_QUANT
+        }
     }
 
-    unsafe fn dealloc(&self, buf: *mut u8, _layout: Layout) {
+    /// Shrinks the allocated buffer `b` to `size` bytes (header included) by splitting off its
+    /// tail and releasing it, provided the tail is big enough to be a free block on its own.
+    unsafe fn trim(&mut self, b: &BHead, size: usize) {
+        if (b.bsize() - size) > (self.size_q() + core::mem::size_of::<BFHead>()) {
+            // The tail becomes an allocated buffer of its own that is immediately released, so
+            // that put() takes care of combining it with the next buffer if that is free.
+            let bt: &mut BHead = BHead::from_addr(b.addr() + size);
+            bt.set_prevfree(0);
+            bt.set_bsize(b.bsize() - size);
+            bt.set_allocated(true);
+            b.set_bsize(size);
+            self.totalloc -= bt.bsize();
+            // The buffer is still there, so this can't free a whole pool.
+            self.put((bt.addr() + self.head_size()) as *mut u8);
+        }
+    }
+
+    /// Releases the buffer `buf` and puts it back on the free lists, combining it with its
+    /// neighbors in memory if they are free. This is brel() without the accounting. If this frees
+    /// a whole acquired pool, returns the pool that is to be given to the release function.
+    unsafe fn put(&mut self, buf: *mut u8) -> Option<(usize, usize)> {
+        assert!(!buf.is_null(), "FspAlloc.put() deallocating a null buffer");
+        // alloc() always places the header at the same distance in front of the user part, even
+        // for aligned requests (the alignment padding is either returned to the free list in front
+        // of the header or left at the end of the buffer), so the header is found the same way for
+        // any alignment.
         assert!(
-            !buf.is_null(),
-            "FspAlloc.dealloc() deallocating a null buffer"
+            (buf as usize) & (SIZE_QUANT - 1) == 0,
+            "FspAlloc.put() deallocating a misaligned buffer"
         );
-        let mut b: &mut BFHead = BFHead::from_addr((buf as usize) - core::mem::size_of::<BHead>());
+        let mut b: &mut BFHead = BFHead::from_addr((buf as usize) - self.head_size());
 
         // Buffer size must be negative, indicating that the buffer is
         // allocated.
 
         assert!(
             b.is_allocated(),
-            "FspAlloc.dealloc() deallocating a free buffer"
+            "FspAlloc.put() deallocating a free buffer"
         );
 
         // Back pointer in next buffer must be zero, indicating the
         // same thing:
         assert!(
             BHead::from_addr(b.addr() + b.bsize()).prevfree() == 0,
-            "FspAlloc.dealloc() deallocating a free buffer"
+            "FspAlloc.put() deallocating a free buffer"
+        );
+
+        self.poison(
+            b.addr() + core::mem::size_of::<BFHead>(),
+            b.addr() + b.bsize(),
         );
 
         // If the back link is nonzero, the previous buffer is free.
@@ -413,28 +1285,20 @@ unsafe impl GlobalAlloc for FspAlloc {
             // Make the previous buffer the one we're working on.
             assert!(
                 BHead::from_addr(b.addr() - b.prevfree()).bsize() == b.prevfree(),
-                "FspAlloc.dealloc() inconsistent buffer information"
+                "FspAlloc.put() inconsistent buffer information"
             );
+            let addr = b.addr();
             b = BFHead::from_addr(b.addr() - b.prevfree());
-            b.set_bsize(b.bsize() + size);
+            self.resize_free(b, b.bsize() + size);
             b.set_allocated(false);
+            // The header of the released buffer is now part of the free one.
+            self.poison(addr, addr + core::mem::size_of::<BFHead>());
         } else {
             // The previous buffer isn't allocated. Insert this buffer
             // on the free list as an isolated free block.
-            assert!(
-                self.freelist.blink_ref().flink_ref().eq(&self.freelist),
-                "FspAlloc.dealloc() inconsistent list formation"
-            );
-            assert!(
-                self.freelist.flink_ref().blink_ref().eq(&self.freelist),
-                "FspAlloc.dealloc() inconsistent list formation"
-            );
-            b.set_flink(&self.freelist);
-            b.set_blink(&self.freelist.blink_ref());
-            self.freelist.set_blink(b);
-            b.blink_mut_ref().set_flink(b);
             b.set_bsize(b.bsize());
             b.set_allocated(false);
+            self.link(b);
         }
 
         // Now we look at the next buffer in memory, located by advancing from
@@ -448,19 +1312,11 @@ unsafe impl GlobalAlloc for FspAlloc {
             // its size to that of our buffer.
             assert!(
                 BHead::from_addr(bn.addr() + bn.bsize()).prevfree() == bn.bsize(),
-                "FspAlloc.dealloc() inconsistent allocation state"
-            );
-            assert!(
-                bn.blink_ref().flink_ref().eq(bn),
-                "FspAlloc.dealloc() incorrect list formation"
-            );
-            assert!(
-                bn.flink_ref().blink_ref().eq(bn),
-                "FspAlloc.dealloc() incorrect list formation"
+                "FspAlloc.put() inconsistent allocation state"
             );
-            bn.blink_mut_ref().set_flink(bn.flink_ref());
-            bn.flink_mut_ref().set_blink(bn.blink_ref());
-            b.set_bsize(b.bsize() + bn.bsize());
+            self.unlink(bn);
+            self.resize_free(b, b.bsize() + bn.bsize());
+            self.poison(bn.addr(), bn.addr() + core::mem::size_of::<BFHead>());
 
             // Finally, advance to the buffer that follows the newly
             // consolidated free block. We must set its backpointer to the
@@ -476,8 +1332,130 @@ unsafe impl GlobalAlloc for FspAlloc {
         // to this buffer; the previous free buffer in memory.
         assert!(
             bn.is_allocated(),
-            "FspAlloc.dealloc() inconsistent allocation state"
+            "FspAlloc.put() inconsistent allocation state"
         );
         bn.set_prevfree(b.bsize());
+
+        // If the free block is followed by the end sentinel and isn't
+        // preceded by anything, it spans a whole pool, which can go back to
+        // where it came from.
+        if bn.bsize() == ESENT {
+            return self.unchain_pool(b);
+        }
+
+        None
+    }
+
+    /// Allocates a buffer for `layout` from the pools as they are. This is bget() without the
+    /// compaction and the acquisition of new pools, which must not happen with the lock held.
+    unsafe fn get(&mut self, layout: &Layout) -> *mut u8 {
+        let buf = self.scan(self.buf_size(layout.size()), self.buf_align(layout.align()));
+        if !buf.is_null() {
+            let b: &mut BHead = BHead::from_addr(buf as usize - self.head_size());
+            self.set_guards(b, layout.size());
+            self.count_alloc(b.bsize());
+            self.numget += 1;
+            #[cfg(feature = "heap_track")]
+            {
+                let linear_id = crate::plat_my_core_pos() as usize;
+                b.seq = ALLOC_SEQ.fetch_add(1, Ordering::SeqCst) + 1;
+                b.tag = ALLOC_TAGS[linear_id];
+                if b.tag != 0 {
+                    TAGGED_ALLOCS[linear_id] += 1;
+                }
+            }
+        }
+
+        self.check();
+        buf
+    }
+}
+
+/// Zeroes the first `size` bytes of the buffer `buf` that an FspAlloc just allocated. With the
+/// heap_scrub feature, free memory is already zero past the links of the free buffers, so only
+/// what may have held links is cleared.
+pub unsafe fn zero_buf(buf: *mut u8, size: usize) {
+    let start = buf as usize;
+    let mut end = start + size;
+    if SCRUB && REDZONE == 0 {
+        let links_end = start - core::mem::size_of::<BHead>() + core::mem::size_of::<BFHead>();
+        if links_end < end {
+            end = links_end;
+        }
+    }
+    if end > start {
+        core::ptr::write_bytes(buf, 0, end - start);
+    }
+}
+
+unsafe impl GlobalAlloc for FspAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let buf = self.alloc_quiet(layout);
+        if buf.is_null() {
+            debug!("No memory left to allocate");
+        }
+
+        buf
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let buf = self.alloc(layout);
+        if !buf.is_null() {
+            zero_buf(buf, layout.size());
+        }
+
+        buf
+    }
+
+    unsafe fn dealloc(&self, buf: *mut u8, _layout: Layout) {
+        assert!(
+            !buf.is_null(),
+            "FspAlloc.dealloc() deallocating a null buffer"
+        );
+
+        let (pool, relfcn) = {
+            let mut heap = self.heap.lock();
+            let b: &mut BHead = BHead::from_addr((buf as usize) - heap.head_size());
+            assert!(
+                b.is_allocated(),
+                "FspAlloc.dealloc() deallocating a free buffer"
+            );
+            assert!(
+                heap.guards_intact(b),
+                "FspAlloc.dealloc() redzone overwritten"
+            );
+            heap.totalloc -= b.bsize();
+            heap.numrel += 1;
+
+            let pool = heap.put(buf);
+            heap.check();
+            (pool, heap.relfcn)
+        };
+
+        if let (Some((addr, len)), Some(release)) = (pool, relfcn) {
+            release(addr as *mut u8, len);
+        }
+    }
+
+    /// Resizes the buffer in place with resize() whenever possible. Only when growing in place is
+    /// impossible does this fall back to allocating a new buffer, copying and releasing the old
+    /// one.
+    unsafe fn realloc(&self, buf: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.resize(buf, new_size) {
+            return buf;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_buf = self.alloc(new_layout);
+        if !new_buf.is_null() {
+            let copy_size = if layout.size() < new_size {
+                layout.size()
+            } else {
+                new_size
+            };
+            core::ptr::copy_nonoverlapping(buf, new_buf, copy_size);
+            self.dealloc(buf, layout);
+        }
+        new_buf
     }
 }
@@ -0,0 +1,241 @@
+//! These are per-CPU heap arenas on top of FspAlloc. Every core allocates from its own FspAlloc,
+//! indexed by plat_my_core_pos() like FSP_SMC_ARGS, so that cores handling SMCs at the same time
+//! don't all wait on a single lock. A core whose arena is exhausted falls back to a shared
+//! FspAlloc, which also gets any memory added after init(). When that is exhausted too, the
+//! compaction function is called before the shared pool acquires more memory, and both the arena
+//! and the shared pool are tried again after it released something.
+//!
+//! Bad things that should not occur:
+//!
+//! - Allocation
+//!     - Failing while the shared pool still has room for the request
+//! - Deallocation
+//!     - Returning a buffer to an arena other than the one it came from
+//!
+//! A buffer may be freed on any core. The owner is found from the address of the buffer, since
+//! every arena is one contiguous region, and the buffer goes back to the owner under the owner's
+//! lock.
+
+extern crate alloc; // need this due to #![no_std]---for regular Rust, it is by default.
+
+use crate::debug;
+use crate::fsp_alloc::{
+    zero_buf, AcquireFn, AllocError, CompactFn, FspAlloc, FspAllocStats, ReleaseFn,
+};
+use crate::qemu_constants::PLATFORM_CORE_COUNT;
+use crate::spinlock::SpinLock;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Every arena gets 1/ARENA_SHARE of the memory given to init() divided by the number of cores.
+/// The rest goes to the shared pool.
+const ARENA_SHARE: usize = 2;
+
+pub struct FspArenas {
+    arenas: [FspAlloc; PLATFORM_CORE_COUNT],
+    shared: FspAlloc,
+    // These are only set by init(), before the other cores start, and are read on every
+    // deallocation, so they are atomics rather than data behind a lock that every core would
+    // take.
+    arena_base: AtomicUsize, // start of the region that is split between the arenas
+    arena_len: AtomicUsize,  // length of the region of each arena
+    compfcn: SpinLock<Option<CompactFn>>,
+}
+
+impl FspArenas {
+    // Like FspAlloc::new(), this just creates a place holder, and init() must be called before
+    // using the instance.
+    pub const fn new() -> FspArenas {
+        FspArenas {
+            arenas: [FspAlloc::new(); PLATFORM_CORE_COUNT],
+            shared: FspAlloc::new(),
+            arena_base: AtomicUsize::new(0),
+            arena_len: AtomicUsize::new(0),
+            compfcn: SpinLock::new(None),
+        }
+    }
+
+    /// Splits the memory between the arenas and the shared pool. This must be called on the
+    /// primary core before any other core uses the allocator.
+    pub fn init(&self, buf: usize, len: usize) {
+        let arena_len = len / ARENA_SHARE / PLATFORM_CORE_COUNT;
+        self.arena_base.store(buf, Ordering::Relaxed);
+        self.arena_len.store(arena_len, Ordering::Relaxed);
+
+        for (i, arena) in self.arenas.iter().enumerate() {
+            arena.init(buf + i * arena_len, arena_len);
+        }
+        let arenas_end = buf + PLATFORM_CORE_COUNT * arena_len;
+        self.shared.init(arenas_end, buf + len - arenas_end);
+    }
+
+    /// Adds more memory to the shared pool.
+    pub fn add_region(&self, buf: usize, len: usize) {
+        self.shared.add_region(buf, len);
+    }
+
+    /// Registers the BECtl-style functions. See FspAlloc::bectl(). The acquisition and release
+    /// functions go to the shared pool, the only one that can grow or shrink. The compaction
+    /// function is kept here rather than in any FspAlloc, since the memory it releases may belong
+    /// to any of them: alloc() calls it itself and tries both the arena of the current core and
+    /// the shared pool again after it.
+    pub fn bectl(
+        &self,
+        compact: Option<CompactFn>,
+        acquire: Option<AcquireFn>,
+        release: Option<ReleaseFn>,
+        pool_incr: usize,
+    ) {
+        *self.compfcn.lock() = compact;
+        self.shared.bectl(None, acquire, release, pool_incr);
+    }
+
+    /// Allocates like alloc(), but says why it failed instead of returning null. See
+    /// FspAlloc::try_alloc().
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        // The arenas are initialized along with the shared pool.
+        self.shared.can_alloc(&layout)?;
+        NonNull::new(unsafe { self.alloc_quiet(layout) }).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Allocates from the arena of the current core or, if it is exhausted, from the shared pool,
+    /// without calling any of the BECtl-style functions.
+    unsafe fn alloc_from_pools(&self, layout: Layout) -> *mut u8 {
+        let buf = self.local().alloc_from_pools(layout);
+        if !buf.is_null() {
+            return buf;
+        }
+
+        self.shared.alloc_from_pools(layout)
+    }
+
+    /// Allocates like alloc(), without reporting a failure on the console. A miss in the arena
+    /// of the current core just moves on to the shared pool. Only when both are exhausted is the
+    /// compaction function called, as in FspAlloc::alloc(), and then the shared pool goes through
+    /// the rest of FspAlloc::alloc() to acquire more memory.
+    unsafe fn alloc_quiet(&self, layout: Layout) -> *mut u8 {
+        let mut buf = self.alloc_from_pools(layout);
+
+        if buf.is_null() {
+            let compfcn = *self.compfcn.lock();
+            if let Some(compact) = compfcn {
+                let mut seq = 0;
+                while buf.is_null() {
+                    seq += 1;
+                    if !compact(layout.size(), seq) {
+                        break;
+                    }
+                    buf = self.alloc_from_pools(layout);
+                }
+            }
+        }
+
+        if buf.is_null() {
+            buf = self.shared.alloc_quiet(layout);
+        }
+
+        buf
+    }
+
+    /// Returns the arena of the current core.
+    fn local(&self) -> &FspAlloc {
+        let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+        &self.arenas[linear_id]
+    }
+
+    /// Returns the allocator that `buf` was allocated from.
+    fn owner(&self, buf: *mut u8) -> &FspAlloc {
+        let addr = buf as usize;
+        let arena_base = self.arena_base.load(Ordering::Relaxed);
+        let arena_len = self.arena_len.load(Ordering::Relaxed);
+        if addr >= arena_base && addr < arena_base + PLATFORM_CORE_COUNT * arena_len {
+            &self.arenas[(addr - arena_base) / arena_len]
+        } else {
+            &self.shared
+        }
+    }
+
+    /// Returns the statistics of all the arenas and the shared pool put together. Since the
+    /// high-water marks are reached at different times, max_alloc is the sum of the high-water
+    /// marks, which is an upper bound on the real one.
+    pub fn stats(&self) -> FspAllocStats {
+        let mut stats = self.shared.stats();
+        for arena in self.arenas.iter() {
+            let arena_stats = arena.stats();
+            stats.cur_alloc += arena_stats.cur_alloc;
+            stats.max_alloc += arena_stats.max_alloc;
+            stats.tot_free += arena_stats.tot_free;
+            if arena_stats.max_free > stats.max_free {
+                stats.max_free = arena_stats.max_free;
+            }
+            stats.num_free += arena_stats.num_free;
+            stats.num_get += arena_stats.num_get;
+            stats.num_rel += arena_stats.num_rel;
+        }
+
+        stats
+    }
+
+    /// Reports the live buffers allocated after `checkpoint` in the arenas and the shared pool.
+    /// See FspAlloc::report().
+    #[cfg(feature = "heap_track")]
+    pub fn report(&self, checkpoint: usize, tag: Option<usize>) -> usize {
+        let mut count = self.shared.report(checkpoint, tag);
+        for arena in self.arenas.iter() {
+            count += arena.report(checkpoint, tag);
+        }
+
+        count
+    }
+
+    /// Validates the arenas and the shared pool. See FspAlloc::validate().
+    pub fn validate(&self) -> bool {
+        self.arenas.iter().all(|arena| arena.validate()) && self.shared.validate()
+    }
+}
+
+unsafe impl GlobalAlloc for FspArenas {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let buf = self.alloc_quiet(layout);
+        if buf.is_null() {
+            debug!("No memory left to allocate");
+        }
+
+        buf
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let buf = self.alloc(layout);
+        if !buf.is_null() {
+            zero_buf(buf, layout.size());
+        }
+
+        buf
+    }
+
+    unsafe fn dealloc(&self, buf: *mut u8, layout: Layout) {
+        self.owner(buf).dealloc(buf, layout);
+    }
+
+    /// Resizes the buffer in place in the allocator it came from whenever possible. Otherwise,
+    /// the new buffer is allocated like any other, from the arena of the current core first.
+    unsafe fn realloc(&self, buf: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.owner(buf).resize(buf, new_size) {
+            return buf;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_buf = self.alloc(new_layout);
+        if !new_buf.is_null() {
+            let copy_size = if layout.size() < new_size {
+                layout.size()
+            } else {
+                new_size
+            };
+            core::ptr::copy_nonoverlapping(buf, new_buf, copy_size);
+            self.dealloc(buf, layout);
+        }
+        new_buf
+    }
+}
@@ -0,0 +1,209 @@
+//! This is a page-frame allocator, a binary buddy system that hands out naturally aligned blocks
+//! of 2^order pages, from 4KB (order 0) to 2MB (order MAX_ORDER). It is the source of slab pages
+//! with the buddy feature.
+//!
+//! Bad things that should not occur:
+//!
+//! - Initialization
+//!     - Managing pages outside of the region given to init()
+//! - Allocation
+//!     - Allocating a block that overlaps with an already-allocated block
+//!     - Handing out a block that is not aligned to its size
+//! - Deallocation
+//!     - Freeing a block that is not allocated, or with the wrong order
+//!     - Leaving two free buddies unmerged
+//!
+//! The state of every page is kept in a static array: the order of the block for the first page
+//! of a block, with PAGE_FREE set if the block is free, and PAGE_TAIL for the other pages. Free
+//! blocks are chained through their first page, one list per order.
+
+use crate::spinlock::SpinLock;
+use core::ptr::null_mut;
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+/// The largest block is 2^MAX_ORDER pages, which is 2MB.
+pub const MAX_ORDER: usize = 9;
+const MAX_BLOCK: usize = PAGE_SIZE << MAX_ORDER;
+
+/// Largest region that init() accepts. The pages are numbered from the MAX_BLOCK boundary below
+/// the region, so the state array also covers up to one MAX_BLOCK in front of it.
+pub const MAX_POOL_SIZE: usize = 0x00400000; // This is 4MB.
+const MAX_PAGES: usize = (MAX_POOL_SIZE + MAX_BLOCK) >> PAGE_SHIFT;
+
+const PAGE_FREE: u8 = 0x80; // set on the first page of a free block
+const PAGE_TAIL: u8 = 0x40; // any page of a block but the first one
+const PAGE_RESERVED: u8 = 0xff; // a page that is not managed
+
+/// Links of a free block, at the start of its first page.
+#[repr(C)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+impl FreeBlock {
+    fn from_addr(addr: usize) -> &'static mut FreeBlock {
+        unsafe { &mut *(addr as *mut FreeBlock) }
+    }
+}
+
+pub struct FspBuddy {
+    pages: SpinLock<FspPages>,
+}
+
+/// The state of an FspBuddy, which is only reached through its lock.
+struct FspPages {
+    base: usize,                        // page 0, MAX_BLOCK aligned
+    free_lists: [usize; MAX_ORDER + 1], // first free block of each order, 0 if there is none
+    pages: [u8; MAX_PAGES],             // state of every page
+    nfree: usize,                       // number of free pages
+}
+
+impl FspBuddy {
+    // Like FspAlloc::new(), this just creates a place holder, and init() must be called before
+    // using the instance.
+    pub const fn new() -> FspBuddy {
+        FspBuddy {
+            pages: SpinLock::new(FspPages {
+                base: 0,
+                free_lists: [0; MAX_ORDER + 1],
+                pages: [PAGE_RESERVED; MAX_PAGES],
+                nfree: 0,
+            }),
+        }
+    }
+
+    /// Gives the pages in [buf, buf + len) to the allocator. This must be called once, before
+    /// using the allocator. Partial pages at either end are left out.
+    pub fn init(&self, buf: usize, len: usize) {
+        let mut pages = self.pages.lock();
+        let start = (buf + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = (buf + len) & !(PAGE_SIZE - 1);
+        assert!(start < end, "FspBuddy.init() region holds no whole page");
+        assert!(
+            end - start <= MAX_POOL_SIZE,
+            "FspBuddy.init() region too big"
+        );
+        pages.base = start & !(MAX_BLOCK - 1);
+
+        // Cut the region into the largest naturally aligned blocks that fit.
+        let mut addr = start;
+        while addr < end {
+            let mut order = MAX_ORDER;
+            while addr & ((PAGE_SIZE << order) - 1) != 0 || addr + (PAGE_SIZE << order) > end {
+                order -= 1;
+            }
+            let first = pages.page_of(addr);
+            for page in first + 1..first + (1 << order) {
+                pages.pages[page] = PAGE_TAIL;
+            }
+            pages.push(addr, order);
+            pages.nfree += 1 << order;
+            addr += PAGE_SIZE << order;
+        }
+    }
+
+    /// Allocates a naturally aligned block of 2^order pages. Returns null if there is none.
+    pub fn alloc_pages(&self, order: usize) -> *mut u8 {
+        if order > MAX_ORDER {
+            return null_mut();
+        }
+
+        let mut pages = self.pages.lock();
+        let mut found = order;
+        while pages.free_lists[found] == 0 {
+            if found == MAX_ORDER {
+                return null_mut();
+            }
+            found += 1;
+        }
+
+        let addr = pages.free_lists[found];
+        pages.remove(addr, found);
+        // Split the block until it has the requested order, freeing the upper halves.
+        while found > order {
+            found -= 1;
+            pages.push(addr + (PAGE_SIZE << found), found);
+        }
+        let page = pages.page_of(addr);
+        pages.pages[page] = order as u8;
+        pages.nfree -= 1 << order;
+
+        addr as *mut u8
+    }
+
+    /// Frees a block of 2^order pages from alloc_pages(), merging it with its buddy for as long
+    /// as the buddy is free too.
+    pub unsafe fn free_pages(&self, buf: *mut u8, order: usize) {
+        let mut pages = self.pages.lock();
+        let mut addr = buf as usize;
+        assert!(
+            addr >= pages.base && addr & ((PAGE_SIZE << order) - 1) == 0,
+            "FspBuddy.free_pages() freeing a misaligned block"
+        );
+        assert!(
+            pages.page_of(addr) < MAX_PAGES && pages.pages[pages.page_of(addr)] == order as u8,
+            "FspBuddy.free_pages() freeing a block that is not allocated with this order"
+        );
+        pages.nfree += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = pages.base + ((pages.page_of(addr) ^ (1 << order)) << PAGE_SHIFT);
+            let page = pages.page_of(buddy);
+            if page >= MAX_PAGES || pages.pages[page] != PAGE_FREE | order as u8 {
+                break;
+            }
+            pages.remove(buddy, order);
+            // The upper half of the merged block is a tail page now.
+            let upper = if buddy > addr { buddy } else { addr };
+            let page = pages.page_of(upper);
+            pages.pages[page] = PAGE_TAIL;
+            if buddy < addr {
+                addr = buddy;
+            }
+            order += 1;
+        }
+        pages.push(addr, order);
+    }
+
+    /// Returns the number of free pages.
+    pub fn free_count(&self) -> usize {
+        self.pages.lock().nfree
+    }
+}
+
+impl FspPages {
+    fn page_of(&self, addr: usize) -> usize {
+        (addr - self.base) >> PAGE_SHIFT
+    }
+
+    /// Puts the block at `addr` on the free list of `order` and marks it free.
+    fn push(&mut self, addr: usize, order: usize) {
+        let b = FreeBlock::from_addr(addr);
+        let head = self.free_lists[order];
+        b.next = head;
+        b.prev = 0;
+        if head != 0 {
+            FreeBlock::from_addr(head).prev = addr;
+        }
+        self.free_lists[order] = addr;
+        let page = self.page_of(addr);
+        self.pages[page] = PAGE_FREE | order as u8;
+    }
+
+    /// Takes the free block at `addr` off the free list of `order`. The caller marks it as what it
+    /// becomes.
+    fn remove(&mut self, addr: usize, order: usize) {
+        let b = FreeBlock::from_addr(addr);
+        if b.prev == 0 {
+            self.free_lists[order] = b.next;
+        } else {
+            FreeBlock::from_addr(b.prev).next = b.next;
+        }
+        if b.next != 0 {
+            FreeBlock::from_addr(b.next).prev = b.prev;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! This is the global allocator. It puts the slab in front of the heap: small requests are served
+//! by FSP_SLAB, which is much faster for tiny objects, and everything else by FSP_ALLOC. The slab
+//! gets its pages straight from FSP_ALLOC (or FSP_PAGES with the buddy feature), not through this
+//! allocator.
+//!
+//! Bad things that should not occur:
+//!
+//! - Deallocation
+//!     - Giving a buffer back to the other backend than the one it came from
+//!
+//! Which backend serves a request only depends on its layout, and dealloc() is given the same
+//! layout as alloc(), so it always finds the backend that owns the buffer.
+//!
+//! With the heap_track feature, every request goes to FSP_ALLOC so that no buffer escapes the leak
+//! reports.
+
+extern crate alloc; // need this due to #![no_std]---for regular Rust, it is by default.
+
+use crate::entrypoints::{FSP_ALLOC, FSP_SLAB};
+use crate::fsp_slab::kmem_slot_size;
+use alloc::alloc::{GlobalAlloc, Layout};
+
+/// Largest request that goes to the slab, in size and in alignment. Such objects fit in 256-byte
+/// slots, which is 15 of them in a slab.
+pub const SLAB_MAX_SIZE: usize = 256;
+
+pub struct FspGlobalAlloc;
+
+impl FspGlobalAlloc {
+    /// Returns true if requests for `layout` go to the slab. Slab objects are aligned to the size
+    /// of their slot, which is at least the alignment of the request.
+    fn is_small(&self, layout: &Layout) -> bool {
+        !cfg!(feature = "heap_track")
+            && layout.size() <= SLAB_MAX_SIZE
+            && kmem_slot_size(layout.size(), layout.align()) <= SLAB_MAX_SIZE
+    }
+}
+
+unsafe impl GlobalAlloc for FspGlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_small(&layout) {
+            FSP_SLAB.kmem_alloc(layout)
+        } else {
+            FSP_ALLOC.alloc(layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.is_small(&layout) {
+            let buf = FSP_SLAB.kmem_alloc(layout);
+            if !buf.is_null() {
+                core::ptr::write_bytes(buf, 0, layout.size());
+            }
+            buf
+        } else {
+            FSP_ALLOC.alloc_zeroed(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, buf: *mut u8, layout: Layout) {
+        if self.is_small(&layout) {
+            FSP_SLAB.kmem_dealloc(buf, layout)
+        } else {
+            FSP_ALLOC.dealloc(buf, layout)
+        }
+    }
+
+    /// Stays in the same buffer if the old and the new size are served by the same slab cache,
+    /// resizes in place in FSP_ALLOC if both sizes are large, and otherwise moves the contents to
+    /// a buffer from the right backend.
+    unsafe fn realloc(&self, buf: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let small = self.is_small(&layout);
+        let new_small = self.is_small(&new_layout);
+
+        if !small && !new_small {
+            return FSP_ALLOC.realloc(buf, layout, new_size);
+        }
+        if small
+            && new_small
+            && kmem_slot_size(layout.size(), layout.align())
+                == kmem_slot_size(new_size, layout.align())
+        {
+            return buf;
+        }
+
+        let new_buf = self.alloc(new_layout);
+        if !new_buf.is_null() {
+            let copy_size = if layout.size() < new_size {
+                layout.size()
+            } else {
+                new_size
+            };
+            core::ptr::copy_nonoverlapping(buf, new_buf, copy_size);
+            self.dealloc(buf, layout);
+        }
+        new_buf
+    }
+}
@@ -3,27 +3,179 @@
 extern crate alloc;
 
 use crate::debug;
-use alloc::alloc::{Layout,alloc,dealloc};
+use crate::qemu_constants::PLATFORM_CORE_COUNT;
+use crate::spinlock::{IrqGuard, SpinLock};
+use alloc::alloc::Layout;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 //const SIZE_QUANT_OBJ: usize = core::mem::size_of::<usize>(); //each obj has to be bigger than a pointer 
-const PAGE_SIZE: usize = 4096; //allocate 4kb of memory from fsp_alloc each time (the size of a page)
+const PAGE_SIZE: usize = 4096; //allocate 4kb of memory each time (the size of a page)
+
+//A slab is 2^order pages. Caches of big objects use bigger slabs so that every slab holds at least SLAB_MIN_OBJS objects,
+//up to slabs of 2^SLAB_MAX_ORDER pages (32KB)
+const SLAB_MAX_ORDER: usize = 3;
+const SLAB_MIN_OBJS: usize = 8;
+//Objects slots are at most a quarter of the biggest slab, so that even those slabs hold a few objects before their Slab header
+const SLAB_MAX_OBJ_SIZE: usize = (PAGE_SIZE << SLAB_MAX_ORDER) / 4;
+//Largest size that kmem_alloc() and create_cache() accept
+pub const KMEM_MAX_SIZE: usize = SLAB_MAX_OBJ_SIZE - SLAB_REDZONE;
+
+//With the slab_debug feature, every object given back is checked to be an object in use of the right cache, and every slot
+//ends with a redzone after the user part, filled with GUARD_BYTE. Free objects are filled with POISON_BYTE, except for
+//their free list link, and checked when they are allocated again, unless the cache has a constructor.
+//The magazines are off, so that every object goes through the checks.
+const SLAB_DEBUG: bool = cfg!(feature = "slab_debug");
+#[cfg(feature = "slab_debug")]
+const SLAB_REDZONE: usize = core::mem::size_of::<usize>();
+#[cfg(not(feature = "slab_debug"))]
+const SLAB_REDZONE: usize = 0;
+const GUARD_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+//Number of free slabs that a cache keeps by default instead of giving their pages back, see set_free_limit()
+const SLAB_FREE_LIMIT: usize = 2;
+
+//Whether each core is getting pages for a slab in kmem_grow(), with the lock of the cache held, see reap()
+static GROWING: [AtomicBool; PLATFORM_CORE_COUNT] = [const { AtomicBool::new(false) }; PLATFORM_CORE_COUNT];
+
+//Every core keeps up to MAG_SIZE free objects of each cache in a magazine, so that it can allocate and free them without
+//taking the lock of the cache. A magazine is refilled from the slabs or emptied into them half of it at a time
+const MAG_SIZE: usize = if SLAB_DEBUG { 0 } else { 8 };
+
+//kmem_alloc() finds the unnamed cache for a size in a table indexed by log2 of the object size, up to SLAB_MAX_OBJ_SIZE
+const SIZE_CLASSES: usize = SLAB_MAX_OBJ_SIZE.trailing_zeros() as usize + 1;
+
+//Slabs are 2^order pages aligned to their size, but without the buddy page allocator they come from FSP_ALLOC, which puts
+//a header before every buffer. There the slabs stop SLAB_TAIL bytes short of their pages so that the header of the next one
+//fits in, instead of costing it a whole slab of alignment padding
+#[cfg(feature = "buddy")]
+const SLAB_TAIL: usize = 0;
+#[cfg(not(feature = "buddy"))]
+const SLAB_TAIL: usize = 128;
+
+//Return the size of a slab of 2^order pages
+fn slab_bytes(order: usize) -> usize {
+    (PAGE_SIZE << order) - SLAB_TAIL
+}
+
+//Get 2^order pages for a slab from the buddy page allocator
+#[cfg(feature = "buddy")]
+unsafe fn page_alloc(order: usize) -> *mut u8 {
+    crate::entrypoints::FSP_PAGES.alloc_pages(order)
+}
+
+#[cfg(feature = "buddy")]
+unsafe fn page_free(page: *mut u8, order: usize) {
+    crate::entrypoints::FSP_PAGES.free_pages(page, order)
+}
+
+//Without the buddy page allocator, get a slab from FSP_ALLOC, aligned to the size of its 2^order pages like the buddy's
+//With heap_track, the slab is allocated without a tag: it belongs to its cache rather than to whoever made the cache grow,
+//and it would otherwise be reported as leaked by them
+#[cfg(not(feature = "buddy"))]
+unsafe fn page_alloc(order: usize) -> *mut u8 {
+    use alloc::alloc::GlobalAlloc;
+    #[cfg(feature = "heap_track")]
+    let tag = crate::fsp_alloc::set_tag(0);
+    let page = crate::entrypoints::FSP_ALLOC.alloc(Layout::from_size_align_unchecked(slab_bytes(order), PAGE_SIZE << order));
+    #[cfg(feature = "heap_track")]
+    crate::fsp_alloc::set_tag(tag);
+    page
+}
+
+#[cfg(not(feature = "buddy"))]
+unsafe fn page_free(page: *mut u8, order: usize) {
+    use alloc::alloc::GlobalAlloc;
+    crate::entrypoints::FSP_ALLOC.dealloc(page, Layout::from_size_align_unchecked(slab_bytes(order), PAGE_SIZE << order))
+}
+
+//Return the order of the slabs for objects of the given size, the smallest one that fits SLAB_MIN_OBJS of them
+fn slab_order(obj_size:usize) -> usize{
+    let mut order = 0;
+    while order < SLAB_MAX_ORDER && Slab::capacity_of(obj_size, order) < SLAB_MIN_OBJS{
+        order += 1;
+    }
+    order
+}
 
 
 pub fn get_power_of_two(size:usize)->usize{
     size.wrapping_next_power_of_two()
 }
 
+//Return the size of the slots that kmem_alloc() uses for objects of the given size and alignment
+//Slabs are aligned to their size and slots are powers of two, so every object is aligned to the size of its slot
+pub fn kmem_slot_size(size:usize, align:usize)->usize{
+    get_power_of_two(size + SLAB_REDZONE).max(align).max(core::mem::size_of::<usize>())
+}
+
+//Fill [start, end) with byte
+fn fill(start:usize, end:usize, byte:u8){
+    if end > start{
+        unsafe { core::ptr::write_bytes(start as *mut u8, byte, end - start); }
+    }
+}
+
+//Return true if [start, end) is filled with byte
+fn filled(start:usize, end:usize, byte:u8) -> bool{
+    (start..end).all(|addr| unsafe { *(addr as *const u8) } == byte)
+}
+
+//Constructor and destructor of the objects of a cache, given a pointer to the object
+//The constructor runs once for every object when a slab is populated, and the destructor when the slab is released,
+//so objects stay constructed while they are free and must be given back to the cache in their constructed state
+pub type CtorFn = fn(*mut u8);
+pub type DtorFn = fn(*mut u8);
+
 pub struct KmemCache{
     slabs_full:usize,			// list of full slabs
     slabs_partial:usize,		// list of partial slabs 
     slabs_free:usize,			// list of free slabs
+    slabs_free_num:usize,       // number of slabs in the free list
+    free_limit:usize,           // free slabs kept before giving their pages back
     name:[char;8],              // name of the cache, length is fixed for better memory management
     next:usize,                 // next cache
     obj_size:usize,             // size of objects
+    slab_order:usize,           // each slab of the cache is 2^slab_order pages
     obj_num:usize,              // number of objects in one slab
     obj_total:usize,            // total capacity of this cache (can be growing or shrinking)
-    obj_active:usize,           // number of active(occupied) object
+    obj_active:usize,           // number of active(occupied) object, including the ones in the magazines
     obj_free:usize,             // number of free(usable) object
+    named:bool,                 // created by create_cache(), kmem_alloc() doesn't use it
+    link_offset:usize,          // where the free list link is in each object slot
+    ctor:Option<CtorFn>,        // object constructor
+    dtor:Option<DtorFn>,        // object destructor
+    lock:SpinLock,              // FSP_SLAB's protects itself and the unnamed caches, a named cache's protects that cache
+    mags:[Magazine;PLATFORM_CORE_COUNT], // free objects kept by each core, only touched by that core
+    classes:[AtomicUsize;SIZE_CLASSES],  // only used by FSP_SLAB, the unnamed cache of each size class, 0 if there is none
+}
+
+//Statistics of a cache, as returned by cache_info()
+#[derive(Clone, Copy)]
+pub struct KmemCacheInfo{
+    pub name:[u8;8],            // name of the cache in ASCII, padded with zeros, all zeros for an unnamed cache
+    pub obj_size:usize,         // size of the object slots
+    pub obj_active:usize,       // objects in use, not counting the ones in the magazines
+    pub obj_total:usize,        // objects in all the slabs
+    pub slabs_full:usize,       // number of slabs in each list
+    pub slabs_partial:usize,
+    pub slabs_free:usize,
+    pub overhead:usize,         // bytes of the slabs that are not usable by objects: headers, unused space and redzones
+}
+
+impl KmemCacheInfo{
+    //Return the name of the cache, empty for an unnamed cache
+    pub fn name(&self) -> &str{
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+//Objects of a cache that a core keeps for itself, see MAG_SIZE
+struct Magazine{
+    count:usize,                // number of objects in objs
+    objs:[usize;MAG_SIZE],      // the objects, as the pointers the user gets
 }
 
 struct Slab{
@@ -34,20 +186,42 @@ struct Slab{
     free:usize,           // number of objects that are free
     capacity:usize,       // total objects in this slab
     obj_size: usize,      // size of each object should be a power of two and bigger than the size of a pointer(usize)
-    start_addr: usize,    // the start address of the slab, should be ths address of the slab instance 
+    link_offset: usize,   // where the free list link is in each object slot
+    start_addr: usize,    // the start address of the slab, the Slab instance is at its end
     end_addr: usize,      // the end address of the slab
     cache_addr: usize     // the address for its owning kmem_cache
 }
 
-#[repr(C)]
-struct Obj{
-    slab_addr:usize,
-    next_obj:usize,       // next free object
-}
+impl Magazine{
+    const fn new() -> Magazine{
+        Magazine{
+            count:0,
+            objs:[0;MAG_SIZE],
+        }
+    }
+
+    //Take the object that was put in last, null if the magazine is empty
+    fn pop(&mut self) -> *mut u8{
+        if self.count == 0{
+            return null_mut()
+        }
+        self.count -= 1;
+        self.objs[self.count] as *mut u8
+    }
 
-impl Obj{
-    fn from_addr( addr: usize) -> &'static mut Obj {
-        unsafe { &mut *(addr as *mut Obj) }
+    //Whether the magazine is less than half full, mag_refill() fills it up to there
+    fn below_half(&self) -> bool{
+        self.count < self.objs.len() / 2
+    }
+
+    //Put an object in, return false if the magazine is full
+    fn push(&mut self, buf: *mut u8) -> bool{
+        if self.count == MAG_SIZE{
+            return false
+        }
+        self.objs[self.count] = buf as usize;
+        self.count += 1;
+        true
     }
 }
 
@@ -65,30 +239,70 @@ impl KmemCache{
         slabs_full : 0 ,			
         slabs_partial :0,		
         slabs_free:0,		
+        slabs_free_num:0,
+        free_limit:SLAB_FREE_LIMIT,
         name:['0','0','0','0','0','0','0','0'],              
         next:0,                 
         obj_size:0,             
+        slab_order:0,
         obj_num:0,              
         obj_total:0,            
         obj_active:0,           
         obj_free:0,             
+        named:false,
+        link_offset:0,
+        ctor:None,
+        dtor:None,
+        lock:SpinLock::new(()),
+        mags:[Magazine::new(); PLATFORM_CORE_COUNT],
+        classes:[const { AtomicUsize::new(0) }; SIZE_CLASSES],
         }
     }
 
     //This must be called for each KmemCache creation.
-    //TO DO: support name initialization 
-    fn init(&self, _obj_size:usize , _addr : usize){
+    //An empty name means an unnamed cache. Longer names are cut to 8 chars.
+    //With a constructor or a destructor, the free list link of an object goes after it rather than over its first word,
+    //so that free objects stay constructed; _obj_size must leave room for it
+    fn init(&self, _name:&str, _obj_size:usize, _link_offset:usize, _ctor:Option<CtorFn>, _dtor:Option<DtorFn>, _addr : usize){
         //debug!("KmemCache init addr{} size{}", _addr, _obj_size);
         self.as_mut_ref().slabs_full = 0 ;			
         self.as_mut_ref().slabs_partial = 0;		
         self.as_mut_ref().slabs_free = 0;			
-        self.as_mut_ref().name = ['0','0','0','0','0','0','0','0'];           
+        self.as_mut_ref().slabs_free_num = 0;
+        self.as_mut_ref().free_limit = SLAB_FREE_LIMIT;
+        self.as_mut_ref().name = ['\0';8];
+        for (i, c) in _name.chars().take(8).enumerate(){
+            self.as_mut_ref().name[i] = c;
+        }
+        self.as_mut_ref().named = !_name.is_empty();
+        self.as_mut_ref().link_offset = _link_offset;
+        self.as_mut_ref().ctor = _ctor;
+        self.as_mut_ref().dtor = _dtor;
         self.as_mut_ref().next = 0;             
         self.as_mut_ref().obj_size = _obj_size;             
+        self.as_mut_ref().slab_order = slab_order(_obj_size);
         self.as_mut_ref().obj_num = 0;            
         self.as_mut_ref().obj_total = 0;           
         self.as_mut_ref().obj_active = 0; 
         self.as_mut_ref().obj_free = 0;     
+        self.as_mut_ref().lock = SpinLock::new(());
+        for mag in self.as_mut_ref().mags.iter_mut(){
+            mag.count = 0;
+        }
+    }
+
+    //Return the end of the part of an object slot that the user owns, relative to the slot
+    fn obj_end(&self) -> usize{
+        if self.ctor.is_some() || self.dtor.is_some(){
+            self.link_offset - SLAB_REDZONE
+        }else{
+            self.obj_size - SLAB_REDZONE
+        }
+    }
+
+    //Return the name of the cache, empty for an unnamed cache
+    pub fn name(&self) -> [char;8]{
+        self.name
     }
 
     /*pub fn kmem_check_name(&self, _name:&String)->bool{
@@ -108,30 +322,23 @@ impl KmemCache{
 
         //as the FSP_SLAB holds all other KmemCaches, we should set its size to the size of KmemCache
         self.as_mut_ref().name = ['t','h','e','m','a','i','n','K'];
-        self.as_mut_ref().obj_size = get_power_of_two(core::mem::size_of::<KmemCache>() + core::mem::size_of::<usize>());
+        self.as_mut_ref().obj_size = kmem_slot_size(core::mem::size_of::<KmemCache>(), core::mem::align_of::<KmemCache>());
+        self.as_mut_ref().slab_order = slab_order(self.obj_size);
         
         //debug!("main_init objsize{}",self.obj_size);
 
         // at the begining of the program, allocate one slab to hold future KmemCaches
-        self.kmem_grow();
+        assert!(self.kmem_grow(), "KmemCache.main_init() no page for the first slab");
        // debug!("main_init addr{}",self.slabs_free);
     }
 
-    //Search all caches in FSP_SLAB
-    //Return the address of the KmemCache which size is equal to the input size
+    //Look up the size class table of FSP_SLAB
+    //Return the address of the unnamed KmemCache which size is equal to the input size
     fn main_search_kmem(&self,size:usize) -> Option<usize>{
-        
-        let mut ptr : usize = self.next;
-
-        while ptr!=0{
-            let _kmem_cache: &mut KmemCache = KmemCache::from_addr(ptr as usize);
-            if _kmem_cache.obj_size == size{
-                return Some(ptr)
-            }
-            ptr = _kmem_cache.next;
+        match self.classes[size.trailing_zeros() as usize].load(Ordering::Acquire){
+            0=>None,
+            ptr=>Some(ptr)
         }
-
-        None
     }
 
     //Seach for a slab that has free object in a given cache and return a pointer to the allocated memory
@@ -147,7 +354,9 @@ impl KmemCache{
             if slab.get_free() -1 <= 0 {
                 self.kmem_put_partial_into_full();
             }
-            return Some(slab.slab_alloc())
+            let buf = slab.slab_alloc();
+            self.debug_check_poison(buf);
+            return Some(buf)
         }
 
         let ptr : usize = self.slabs_free;
@@ -155,7 +364,9 @@ impl KmemCache{
         if ptr!=0 {
             let slab: &mut Slab = Slab::from_addr(ptr as usize);
             self.kmem_put_free_into_partial();
-            return Some(slab.slab_alloc())
+            let buf = slab.slab_alloc();
+            self.debug_check_poison(buf);
+            return Some(buf)
         }
         None
     }
@@ -163,11 +374,34 @@ impl KmemCache{
     //Allcoate a piece of memory for a given Layout
     //If the cache with the size of the Layout is full, FSP_SLAB will call kmem_grow() to extend its capacity by one slab
     //If not cache has the same size, FSP_SLAB will call create_kmem_cache() to create one with the given size;
+    //Return null if no page can be obtained for a new slab, or if the size is over KMEM_MAX_SIZE or the alignment over SLAB_MAX_OBJ_SIZE
     //
     //This could only be called from FSP_SLAB, calling this function from any other KmemCache will probably casue infinity loop.
+    //It takes an object from the magazine of the current core if there is one, and otherwise it takes FSP_SLAB's lock,
+    //so it can be called from all cores at once.
     pub unsafe fn kmem_alloc(&self, layout: Layout)-> *mut u8{
-        let mut size: usize = layout.size();
-        size = get_power_of_two(size + core::mem::size_of::<usize>());
+        if layout.size() > KMEM_MAX_SIZE || layout.align() > SLAB_MAX_OBJ_SIZE{
+            return null_mut()
+        }
+        let size = kmem_slot_size(layout.size(), layout.align());
+        if let Some(addr) = self.main_search_kmem(size){
+            let buf = KmemCache::from_addr(addr).mag_pop();
+            if !buf.is_null(){
+                return buf
+            }
+        }
+
+        let _guard = self.lock.lock();
+        let buf = self.kmem_alloc_locked(layout);
+        if let Some(addr) = self.main_search_kmem(size){
+            KmemCache::from_addr(addr).mag_refill();
+        }
+        buf
+    }
+
+    //kmem_alloc() with FSP_SLAB's lock already held
+    unsafe fn kmem_alloc_locked(&self, layout: Layout)-> *mut u8{
+        let size = kmem_slot_size(layout.size(), layout.align());
         match self.main_search_kmem(size){
             //found a cache
             Some(addr)=>{
@@ -176,112 +410,543 @@ impl KmemCache{
                     //found a slab with free obj
                     Some(ret)=>{
                         cache.as_mut_ref().obj_free -= 1;
+                        cache.as_mut_ref().obj_active += 1;
                         ret
                     }
                     //No aviable slab, allocate another slab to this cache
                     _=>{
-                        cache.kmem_grow();
-                        self.kmem_alloc(layout)
+                        if !cache.kmem_grow(){
+                            return null_mut()
+                        }
+                        self.kmem_alloc_locked(layout)
                     }
                }
 
             }
             //No such cache in FSP_SLAB, Create one
             _=>{
-                self.create_kmem_cache(size);
-                self.kmem_alloc(layout)
+                if self.create_kmem_cache("", size, 0, None, None).is_none(){
+                    return null_mut()
+                }
+                self.kmem_alloc_locked(layout)
             }
         }
     }
 
-    //
-    pub unsafe fn kmem_dealloc(&self, buf: *mut u8, _layout: Layout) {
-        let obj_addr = buf as usize - core::mem::size_of::<usize>();
-        let slab_ptr = Obj::from_addr(obj_addr).slab_addr;
-        let slab = Slab::from_addr(slab_ptr);
-        let (cache_ptr,ret_info) = slab.slab_dealloc(obj_addr);
-        let cache = KmemCache::from_addr(cache_ptr);
+    //Give a piece of memory from kmem_alloc() back to its slab
+    //Like kmem_alloc(), this could only be called from FSP_SLAB. It puts the object in the magazine of the current core,
+    //and only takes FSP_SLAB's lock to make room in it.
+    //The layout must be the one the object was allocated with, it gives the cache that owns the object
+    pub unsafe fn kmem_dealloc(&self, buf: *mut u8, layout: Layout) {
+        if SLAB_DEBUG{
+            let _guard = self.lock.lock();
+            let cache = self.debug_find_owner(buf, layout);
+            cache.debug_check_obj(buf);
+            #[cfg(feature = "heap_scrub")]
+            cache.scrub(buf);
+            cache.kmem_dealloc_locked(buf);
+            return
+        }
+
+        let cache = match self.main_search_kmem(kmem_slot_size(layout.size(), layout.align())){
+            Some(addr)=>KmemCache::from_addr(addr),
+            None=>panic!("KmemCache.kmem_dealloc() no cache for the layout of {}", buf as usize),
+        };
+        #[cfg(feature = "heap_scrub")]
+        cache.scrub(buf);
+        if cache.mag_push(buf){
+            return
+        }
+
+        let _guard = self.lock.lock();
+        cache.mag_drain(MAG_SIZE / 2);
+        cache.kmem_dealloc_locked(buf)
+    }
+
+    //Return the slab of the cache that the object buf is in
+    //Slabs are aligned to their size, so it is found by masking the address of the object
+    fn slab_of(&self, buf: *mut u8) -> &'static mut Slab{
+        let start = buf as usize & !((PAGE_SIZE << self.slab_order) - 1);
+        Slab::from_addr(start + slab_bytes(self.slab_order) - core::mem::size_of::<Slab>())
+    }
+
+    //With heap_scrub, clear everything the user could have written in an object that is given back, before it can be reused,
+    //and construct it again if the cache keeps its objects constructed
+    #[cfg(feature = "heap_scrub")]
+    unsafe fn scrub(&self, buf: *mut u8){
+        core::ptr::write_bytes(buf, 0, self.obj_end());
+        if let Some(ctor) = self.ctor{
+            ctor(buf);
+        }
+    }
+
+    //With slab_debug and FSP_SLAB's lock held, return the unnamed cache of the layout, which buf must be an object in use of
+    //The slab that buf is in must be one of the full or partial slabs of the cache
+    fn debug_find_owner(&self, buf: *mut u8, layout: Layout) -> &'static mut KmemCache{
+        assert!(buf as usize % core::mem::size_of::<usize>() == 0, "KmemCache: misaligned object at {}", buf as usize);
+        if let Some(addr) = self.main_search_kmem(kmem_slot_size(layout.size(), layout.align())){
+            let cache = KmemCache::from_addr(addr);
+            if cache.debug_has_slab(cache.slab_of(buf) as *const Slab as usize){
+                return cache
+            }
+        }
+        panic!("KmemCache: {} is not an object in use of FSP_SLAB", buf as usize);
+    }
+
+    //Return true if the slab at addr is one of the full or partial slabs of the cache, which are the ones with objects in use
+    fn debug_has_slab(&self, addr:usize) -> bool{
+        for list in [self.slabs_full, self.slabs_partial].iter(){
+            let mut ptr = *list;
+            while ptr != 0{
+                if ptr == addr{
+                    return true
+                }
+                ptr = Slab::from_addr(ptr).next_slab;
+            }
+        }
+        false
+    }
+
+    //With slab_debug and the lock of the cache held, check that buf, whose slab is one of the cache's,
+    //is the start of an object of the slab that is not free, and that its redzone is intact
+    fn debug_check_obj(&self, buf: *mut u8){
+        let obj_addr = buf as usize;
+        let slab = self.slab_of(buf);
+        let first = slab.start_addr;
+        assert!(obj_addr >= first && obj_addr < first + slab.capacity * slab.obj_size && (obj_addr - first) % slab.obj_size == 0,
+            "KmemCache: {} is not the start of an object of its slab", buf as usize);
+        assert!(!slab.is_free(obj_addr), "KmemCache: double free of the object at {}", buf as usize);
+        let redzone = obj_addr + self.obj_end();
+        assert!(filled(redzone, redzone + SLAB_REDZONE, GUARD_BYTE), "KmemCache: redzone of the object at {} overwritten", buf as usize);
+    }
+
+    //With slab_debug, fill the free object buf with the poison pattern, but for its first word which holds the free list link
+    //Objects of caches with a constructor are left alone
+    fn debug_poison(&self, buf: *mut u8){
+        if SLAB_DEBUG && self.ctor.is_none() && self.dtor.is_none(){
+            fill(buf as usize + core::mem::size_of::<usize>(), buf as usize + self.obj_end(), POISON_BYTE);
+        }
+    }
+
+    //With slab_debug, check that the object buf that is being allocated wasn't written to since it was freed
+    fn debug_check_poison(&self, buf: *mut u8){
+        if SLAB_DEBUG && self.ctor.is_none() && self.dtor.is_none(){
+            assert!(filled(buf as usize + core::mem::size_of::<usize>(), buf as usize + self.obj_end(), POISON_BYTE),
+                "KmemCache: the free object at {} was written to", buf as usize);
+        }
+    }
+
+    //Return the magazine of the current core
+    //Only that core uses it, so it needs no lock, but interrupts must be masked while it is used, as they are with a lock held,
+    //since a yielding SMC may be preempted by another SMC that uses it too
+    fn magazine(&self) -> *mut Magazine{
+        let linear_id = unsafe { crate::plat_my_core_pos() } as usize;
+        &self.mags[linear_id] as *const Magazine as *mut Magazine
+    }
+
+    //Take an object from the magazine of the current core without the lock of the cache, null if it is empty
+    fn mag_pop(&self) -> *mut u8{
+        let _irq = IrqGuard::new();
+        unsafe { (*self.magazine()).pop() }
+    }
+
+    //Put an object in the magazine of the current core without the lock of the cache, return false if it is full
+    fn mag_push(&self, buf: *mut u8) -> bool{
+        let _irq = IrqGuard::new();
+        unsafe { (*self.magazine()).push(buf) }
+    }
+
+    //With the lock of the cache held, move free objects from the slabs into the magazine of the current core until it is half full
+    unsafe fn mag_refill(&self){
+        let mag = &mut *self.magazine();
+        while mag.below_half(){
+            match self.kmem_search_slab(){
+                Some(buf)=>{
+                    self.as_mut_ref().obj_free -= 1;
+                    self.as_mut_ref().obj_active += 1;
+                    mag.push(buf);
+                }
+                None=>break
+            }
+        }
+    }
+
+    //With the lock of the cache held, give the objects in the magazine of the current core back to their slabs until keep are left
+    unsafe fn mag_drain(&self, keep:usize){
+        let mag = &mut *self.magazine();
+        while mag.count > keep{
+            self.kmem_dealloc_locked(mag.pop());
+        }
+    }
+
+    //Calling to FSP_SLAB, give the objects in the magazines of the current core back to their slabs, in every cache
+    //This must be called before the core is turned off or suspended, so that its objects can be used by the other cores meanwhile
+    pub unsafe fn flush_magazines(&self){
+        let _guard = self.lock.lock();
+        let mut ptr = self.next;
+        while ptr != 0{
+            let cache = KmemCache::from_addr(ptr);
+            //the unnamed caches are protected by FSP_SLAB's lock, which is already held
+            let _cache_guard = if cache.named { Some(cache.lock.lock()) } else { None };
+            cache.mag_drain(0);
+            ptr = cache.next;
+        }
+    }
+
+    //Set how many free slabs the cache keeps for later instead of giving their pages back, and give back the ones above the limit
+    //Called on FSP_SLAB, it sets the limit of FSP_SLAB itself and of the unnamed caches, and the one new caches start with
+    pub unsafe fn set_free_limit(&self, limit:usize){
+        let _guard = self.lock.lock();
+        self.as_mut_ref().free_limit = limit;
+        self.kmem_trim();
+        if self.named{
+            return
+        }
+        let mut ptr = self.next;
+        while ptr != 0{
+            let cache = KmemCache::from_addr(ptr);
+            if !cache.named{
+                cache.free_limit = limit;
+                cache.kmem_trim();
+            }
+            ptr = cache.next;
+        }
+    }
+
+    //Calling to FSP_SLAB, give the pages of the free slabs of all the caches back to the page source, whatever their limit
+    //Return the number of slabs that were freed, or None if FSP_SLAB's lock is held by another core, so that it is worth
+    //calling again
+    //This only tries to take the locks, and skips what is locked, since FSP_ALLOC calls it when it runs out of memory,
+    //which can happen while a cache of the current core is growing. Then the locks can be its own, and it returns Some(0)
+    //instead of None, since waiting for them would never end.
+    //Only FSP_ALLOC calls it. With the buddy feature, the free slabs are not reaped when FSP_PAGES runs out of pages,
+    //the caches only give back the ones above their free limit.
+    pub unsafe fn reap(&self) -> Option<usize>{
+        let _guard = match self.lock.try_lock(){
+            Some(guard)=>guard,
+            None=>{
+                let linear_id = crate::plat_my_core_pos() as usize;
+                if GROWING[linear_id].load(Ordering::Relaxed){
+                    return Some(0)
+                }
+                return None
+            }
+        };
+        let mut num = self.kmem_reap();
+        let mut ptr = self.next;
+        while ptr != 0{
+            let cache = KmemCache::from_addr(ptr);
+            if !cache.named{
+                num += cache.kmem_reap();
+            }else if let Some(_cache_guard) = cache.lock.try_lock(){
+                num += cache.kmem_reap();
+            }
+            ptr = cache.next;
+        }
+        Some(num)
+    }
+
+    //Calling to FSP_SLAB, return the statistics of the cache at the given position in the list of caches, None past its end
+    //New caches are put at the front of the list, so the position of a cache changes when caches are created
+    pub unsafe fn cache_info(&self, index:usize) -> Option<KmemCacheInfo>{
+        let _guard = self.lock.lock();
+        let mut ptr = self.next;
+        for _i in 0..index{
+            if ptr == 0{
+                return None
+            }
+            ptr = KmemCache::from_addr(ptr).next;
+        }
+        if ptr == 0{
+            return None
+        }
+        let cache = KmemCache::from_addr(ptr);
+        let _cache_guard = if cache.named { Some(cache.lock.lock()) } else { None };
+        Some(cache.info())
+    }
+
+    //Return the statistics of the cache, with the lock of the cache held
+    //The magazines of the other cores can change meanwhile, so the number of objects in use is only a snapshot
+    fn info(&self) -> KmemCacheInfo{
+        let mut name = [0u8;8];
+        for (i, c) in self.name.iter().enumerate(){
+            if *c == '\0'{
+                break
+            }
+            name[i] = if c.is_ascii() { *c as u8 } else { b'?' };
+        }
+        let in_mags: usize = self.mags.iter().map(|mag| mag.count).sum();
+        let slabs_full = Slab::count(self.slabs_full);
+        let slabs_partial = Slab::count(self.slabs_partial);
+        let slabs = slabs_full + slabs_partial + self.slabs_free_num;
+        KmemCacheInfo{
+            name:name,
+            obj_size:self.obj_size,
+            obj_active:self.obj_active - in_mags,
+            obj_total:self.obj_total,
+            slabs_full:slabs_full,
+            slabs_partial:slabs_partial,
+            slabs_free:self.slabs_free_num,
+            overhead:slabs * slab_bytes(self.slab_order) - self.obj_total * self.obj_end(),
+        }
+    }
+
+    //Give an object back to its slab, called on the cache that owns it with the lock of the cache already held
+    unsafe fn kmem_dealloc_locked(&self, buf: *mut u8) {
+        let slab = self.slab_of(buf);
+        let slab_ptr = slab as *const Slab as usize;
+        self.debug_poison(buf);
+        let (_,ret_info) = slab.slab_dealloc(buf as usize);
+        //count the object before the slab can be freed with it
+        self.as_mut_ref().obj_free += 1;
+        self.as_mut_ref().obj_active -= 1;
         if ret_info == 0{
-            cache.kmem_put_full_into_partial(slab_ptr);
+            self.kmem_put_full_into_partial(slab_ptr);
         }
         if ret_info == 2{
-            cache.kmem_put_partial_into_free(slab_ptr);
+            self.kmem_put_partial_into_free(slab_ptr);
+        }
+    }
+
+    //Calling to FSP_SLAB, create a named cache for objects of the given size and alignment, and return a handle to it
+    //Unlike the caches that kmem_alloc() creates for each size, a named cache only holds the objects that are allocated
+    //with cache_alloc() on its handle, so a subsystem can keep its objects to itself
+    //The optional constructor and destructor are run when slabs are populated and released, see CtorFn
+    //Objects are aligned to align, which must be a power of two
+    //Return None if the objects don't fit in a slot of SLAB_MAX_OBJ_SIZE, or if no page can be obtained for it
+    pub unsafe fn create_cache(&self, name:&str, size:usize, align:usize, ctor:Option<CtorFn>, dtor:Option<DtorFn>) -> Option<&'static KmemCache>{
+        assert!(!name.is_empty(), "KmemCache.create_cache() a named cache needs a name");
+        assert!(align.is_power_of_two(), "KmemCache.create_cache() alignment is not a power of two");
+        if size > KMEM_MAX_SIZE || align > SLAB_MAX_OBJ_SIZE{
+            return None
+        }
+        let word = core::mem::size_of::<usize>();
+        let _guard = self.lock.lock();
+        let mut link_offset = 0;
+        let mut obj_size = kmem_slot_size(size, align);
+        //constructed objects keep their contents while they are free, so the link goes after the object
+        if ctor.is_some() || dtor.is_some(){
+            link_offset = ((size + word - 1) & !(word - 1)) + SLAB_REDZONE;
+            obj_size = get_power_of_two(link_offset + word).max(align);
+        }
+        if obj_size > SLAB_MAX_OBJ_SIZE{
+            return None
+        }
+        match self.create_kmem_cache(name, obj_size, link_offset, ctor, dtor){
+            Some(addr)=>Some(KmemCache::from_addr(addr)),
+            None=>None,
+        }
+    }
+
+    //Calling to FSP_SLAB, destroy a named cache from create_cache(): give the pages of all its slabs back, unlink it
+    //from the list of caches and give its descriptor back to FSP_SLAB
+    //Return false and leave the cache alone if some of its objects are still in use, unless force is set, in which case
+    //they are freed with the rest. The destructor runs on every object, in use or not, and with heap_scrub the slabs that
+    //still had objects in use are cleared before their pages are given back, since those objects were never scrubbed.
+    //Nothing may use the cache meanwhile. Every &'static KmemCache handle to it dangles afterwards, so neither the handles
+    //nor the objects of the cache may be used again
+    pub unsafe fn destroy_cache(&self, cache:&'static KmemCache, force:bool) -> bool{
+        assert!(cache.named, "KmemCache.destroy_cache() only named caches can be destroyed");
+        let _guard = self.lock.lock();
+        let cache_addr = cache as *const KmemCache as usize;
+        {
+            let _cache_guard = cache.lock.lock();
+            //the objects in the magazines are free, whatever core they are kept by
+            let in_mags: usize = cache.mags.iter().map(|mag| mag.count).sum();
+            if cache.obj_active > in_mags && !force{
+                return false
+            }
+            for list in [cache.slabs_full, cache.slabs_partial, cache.slabs_free].iter(){
+                let mut ptr = *list;
+                while ptr != 0{
+                    let slab = Slab::from_addr(ptr);
+                    //the Slab header goes away with the pages
+                    ptr = slab.next_slab;
+                    if let Some(dtor) = cache.dtor{
+                        slab.for_each_obj(dtor);
+                    }
+                    let start = slab.start_addr;
+                    #[cfg(feature = "heap_scrub")]
+                    if slab.inuse > 0{
+                        core::ptr::write_bytes(start as *mut u8, 0, slab_bytes(cache.slab_order));
+                    }
+                    page_free(start as *mut u8, cache.slab_order);
+                }
+            }
         }
-        cache.as_mut_ref().obj_free += 1;
+
+        let mut prev: &KmemCache = self;
+        while prev.next != cache_addr{
+            assert!(prev.next != 0, "KmemCache.destroy_cache() the cache is not in the list of FSP_SLAB");
+            prev = KmemCache::from_addr(prev.next);
+        }
+        prev.as_mut_ref().next = cache.next;
+        self.kmem_dealloc_locked(cache_addr as *mut u8);
+        true
     }
 
-    //Calling to FSP_SLAB, Create a KmemCache with a given size.
-    //There should not have two Kmem_caches that share the same size
-    unsafe fn create_kmem_cache(&self, _obj_size:usize) {
+    //Allocate an object from a named cache
+    //Return null if no page can be obtained for a new slab
+    pub unsafe fn cache_alloc(&self)-> *mut u8{
+        let buf = self.mag_pop();
+        if !buf.is_null(){
+            return buf
+        }
+
+        let _guard = self.lock.lock();
+        if self.obj_free == 0 && !self.kmem_grow(){
+            return null_mut()
+        }
+        match self.kmem_search_slab(){
+            Some(ret)=>{
+                self.as_mut_ref().obj_free -= 1;
+                self.as_mut_ref().obj_active += 1;
+                self.mag_refill();
+                ret
+            }
+            None=>null_mut()
+        }
+    }
+
+    //Give an object from cache_alloc() back to its named cache
+    pub unsafe fn cache_dealloc(&self, buf: *mut u8){
+        if SLAB_DEBUG{
+            let _guard = self.lock.lock();
+            assert!(buf as usize % core::mem::size_of::<usize>() == 0, "KmemCache: misaligned object at {}", buf as usize);
+            assert!(self.debug_has_slab(self.slab_of(buf) as *const Slab as usize),
+                "KmemCache.cache_dealloc() {} is not an object in use of the cache", buf as usize);
+            self.debug_check_obj(buf);
+            #[cfg(feature = "heap_scrub")]
+            self.scrub(buf);
+            self.kmem_dealloc_locked(buf);
+            return
+        }
+
+        assert!(self.slab_of(buf).cache_addr == self as *const KmemCache as usize, "KmemCache.cache_dealloc() object from another cache");
+        #[cfg(feature = "heap_scrub")]
+        self.scrub(buf);
+        if self.mag_push(buf){
+            return
+        }
+
+        let _guard = self.lock.lock();
+        self.mag_drain(MAG_SIZE / 2);
+        self.kmem_dealloc_locked(buf)
+    }
+
+    //Calling to FSP_SLAB, Create a KmemCache with a given name and size and return its address.
+    //There should not have two unnamed Kmem_caches that share the same size
+    //Return None if no page can be obtained for it
+    unsafe fn create_kmem_cache(&self, _name:&str, _obj_size:usize, _link_offset:usize, _ctor:Option<CtorFn>, _dtor:Option<DtorFn>) -> Option<usize> {
         //try allocate memory from FSP_SLAB's slabs
         match self.kmem_search_slab(){
             //found memory
             Some(addr)=>{
                 debug!("create_kmem_cache at {}", addr as usize);
+                self.as_mut_ref().obj_free -= 1;
+                self.as_mut_ref().obj_active += 1;
                 let cache = KmemCache::from_addr(addr as usize);
-                cache.init(_obj_size,addr as usize);
+                cache.init(_name,_obj_size,_link_offset,_ctor,_dtor,addr as usize);
+                cache.free_limit = self.free_limit;
+                if !cache.kmem_grow(){
+                    self.kmem_dealloc_locked(addr);
+                    return None
+                }
                 cache.next = self.next;
                 self.as_mut_ref().next = addr as usize;
-                cache.kmem_grow();
+                if !cache.named{
+                    self.classes[_obj_size.trailing_zeros() as usize].store(addr as usize, Ordering::Release);
+                }
+                Some(addr as usize)
             }
             //All FSP_SLAB's slabs are full, which wouldn't normally happen. 
             //
             //The first slab we created for FSP_SLAB at the beginning has capacity around 30
             //we normally don't have that many different types of structs that noffseteed SLAB
             None=>{
-                self.kmem_grow();
-                self.create_kmem_cache(_obj_size)
+                if !self.kmem_grow(){
+                    return None
+                }
+                self.create_kmem_cache(_name,_obj_size,_link_offset,_ctor,_dtor)
             }
         }
 
     }
 
-    //Allocate 4kb of memory from FSP_ALLOC and create a new slab for the cache
-    unsafe fn kmem_grow(&self){
+    //Get 2^slab_order pages from page_alloc() and create a new slab for the cache
+    //Return false if there are no pages left
+    unsafe fn kmem_grow(&self) -> bool{
         debug!("kmem_grow free_obj{} freehead{} partialhead{} fullhead{}", self.obj_free,self.slabs_free,self.slabs_partial,self.slabs_full);
-        let _layout = Layout::from_size_align(PAGE_SIZE, 2);
-        match _layout{
-            Ok(layout)=>{
-                let ptr : *mut u8 = alloc(layout);
-
-                let slab: &mut Slab = Slab::from_addr(ptr as usize);
-                slab.init(self.obj_size, ptr.clone() as usize, self as *const KmemCache as usize );
-
-                if self.slabs_free!=0{
-                    Slab::from_addr(self.slabs_free).set_prev(ptr as usize);
-                    slab.set_next(self.slabs_free);
-                }
+        let linear_id = crate::plat_my_core_pos() as usize;
+        GROWING[linear_id].store(true, Ordering::Relaxed);
+        let ptr : *mut u8 = page_alloc(self.slab_order);
+        GROWING[linear_id].store(false, Ordering::Relaxed);
+        if ptr.is_null(){
+            debug!("kmem_grow out of pages");
+            return false
+        }
 
-                self.as_mut_ref().slabs_free = ptr as usize;
+        let slab: &mut Slab = self.slab_of(ptr);
+        let slab_ptr = slab as *const Slab as usize;
+        slab.init(self.obj_size, self.link_offset, self.slab_order, ptr.clone() as usize, self as *const KmemCache as usize );
+        if let Some(ctor) = self.ctor{
+            slab.for_each_obj(ctor);
+        }
+        if SLAB_DEBUG{
+            slab.for_each_obj(|buf|{
+                let redzone = buf as usize + self.obj_end();
+                fill(redzone, redzone + SLAB_REDZONE, GUARD_BYTE);
+                self.debug_poison(buf);
+            });
+        }
 
-                self.as_mut_ref().obj_free += slab.get_free();
-                self.as_mut_ref().obj_total += slab.get_capacity();
-            }
-            Err(_)=>{debug!("Slab failed to construct layout");}
+        if self.slabs_free!=0{
+            Slab::from_addr(self.slabs_free).set_prev(slab_ptr);
+            slab.set_next(self.slabs_free);
         }
 
+        self.as_mut_ref().slabs_free = slab_ptr;
+        self.as_mut_ref().slabs_free_num += 1;
+
+        self.as_mut_ref().obj_free += slab.get_free();
+        self.as_mut_ref().obj_total += slab.get_capacity();
+        true
     }
 
-    //Free the first slab in the free list
-    //For now, it is only called by kmem_put_partial_into_free(), whenever we have more than one free slab, we will free til we have only one.
-    unsafe fn kmem_shrink(&self){
-        if self.slabs_free!=0{
+    //Free the first slab in the free list if the cache has more than keep free slabs, return whether it did
+    //It is called by kmem_trim() and kmem_reap(), which decide how many free slabs are kept.
+    unsafe fn kmem_shrink(&self, keep:usize) -> bool{
+        if self.slabs_free!=0 && self.slabs_free_num > keep{
             let slab = Slab::from_addr(self.slabs_free);
             self.as_mut_ref().slabs_free = slab.next_slab;
+            self.as_mut_ref().slabs_free_num -= 1;
             if self.slabs_free != 0{
                 Slab::from_addr(self.slabs_free).set_prev(0);
             }
-            let _layout = Layout::from_size_align(PAGE_SIZE, 2);
-            match _layout{
-                Ok(layout)=>{
-                    self.as_mut_ref().obj_total -= slab.capacity;
-                    self.as_mut_ref().obj_free -= slab.free; 
-                    debug!("kmem_shrink freeing slab at {} next{}", slab.start_addr,slab.next_slab);
-                    dealloc(slab.start_addr as *mut u8,layout);
-                }
-                _=>{debug!("Slab failed to construct layout");}
+            self.as_mut_ref().obj_total -= slab.capacity;
+            self.as_mut_ref().obj_free -= slab.free; 
+            debug!("kmem_shrink freeing slab at {} next{}", slab.start_addr,slab.next_slab);
+            if let Some(dtor) = self.dtor{
+                slab.for_each_obj(dtor);
             }
+            page_free(slab.start_addr as *mut u8, self.slab_order);
+            return true
+        }
+        false
+    }
+
+    //Free slabs until the cache keeps no more than free_limit of them
+    unsafe fn kmem_trim(&self){
+        while self.kmem_shrink(self.free_limit){}
+    }
+
+    //Free all the free slabs of the cache, return how many there were
+    unsafe fn kmem_reap(&self) -> usize{
+        let mut num = 0;
+        while self.kmem_shrink(0){
+            num += 1;
         }
+        num
     }
     
     // this will always be the first slab in partial list
@@ -316,6 +981,7 @@ impl KmemCache{
             Slab::from_addr(slab.next_slab).set_prev( 0 );
         }
         self.as_mut_ref().slabs_free = slab.next_slab;
+        self.as_mut_ref().slabs_free_num -= 1;
         slab.set_next(self.slabs_partial);
         self.as_mut_ref().slabs_partial = tmp;
         slab.as_mut_ref().set_prev(0);
@@ -325,25 +991,26 @@ impl KmemCache{
     unsafe fn kmem_put_partial_into_free(&self,addr:usize){
         let slab = Slab::from_addr(addr);
         //debug!("kmem_put_partial_into_free addr{} slab.next{} slab.prev{} self.partial{} self.free{}", addr, slab.next_slab,slab.prev_slab, self.slabs_partial, self.slabs_free);
-        if self.slabs_free != 0{
-            self.kmem_shrink();
-        }
-
         if slab.prev_slab!=0{
             Slab::from_addr(slab.prev_slab).set_next(slab.next_slab);
         }
+        if slab.next_slab != 0 {
+            Slab::from_addr(slab.next_slab).set_prev(slab.prev_slab);
+        }
         if self.slabs_partial == addr{
             self.as_mut_ref().slabs_partial = slab.next_slab;
-            if slab.next_slab != 0 {
-                Slab::from_addr(slab.next_slab).set_prev(0);
-            }
         }
 
+        slab.set_prev(0);
         slab.set_next(self.slabs_free);
         if self.slabs_free != 0{
             Slab::from_addr(self.slabs_free).set_prev(addr.clone());
         }
         self.as_mut_ref().slabs_free = addr;
+        self.as_mut_ref().slabs_free_num += 1;
+        //only keep up to free_limit free slabs, so that a cache whose use goes up and down around a slab boundary doesn't
+        //get and give back a page every time
+        self.kmem_trim();
        // debug!("kmem_put_partial_into_free after addr{} slab.next{} slab.prev{} self.partial{} self.free{}", addr, slab.next_slab,slab.prev_slab, self.slabs_partial, self.slabs_free);
     }
 
@@ -354,13 +1021,14 @@ impl KmemCache{
         if slab.prev_slab!=0{
             Slab::from_addr(slab.prev_slab).set_next(slab.next_slab);
         }
+        if slab.next_slab != 0 {
+            Slab::from_addr(slab.next_slab).set_prev(slab.prev_slab);
+        }
         if self.slabs_full == addr{
             self.as_mut_ref().slabs_full = slab.next_slab;
-            if slab.next_slab != 0 {
-                Slab::from_addr(slab.next_slab).set_prev(0);
-            }
         }
 
+        slab.set_prev(0);
         slab.set_next(self.slabs_partial);
         if self.slabs_partial != 0{
             Slab::from_addr(self.slabs_partial).set_prev(addr.clone());
@@ -373,15 +1041,14 @@ impl KmemCache{
 impl Slab{
     //The basic unit of Slab allocation
     //One slab is 4kb and size of each object is a power of two, and no smaller than a pointer(usize)
-    //The instance of Slab is put into the beginning of slab, and it takes one or more complete objects
+    //The instance of Slab is put at the end of slab, and it takes one or more complete objects
     //
-    //At the beginning, all objects are free and since objects are no smaller than a pointer, we put a pointer at the beginning of each object
+    //At the beginning, all objects are free and since objects are no smaller than a pointer, we put a pointer at link_offset of each object
     //which pointing to the address of next free objects
     //doing this, the process of allocating is a one-step thing--return the head of free list and move the head to the next
     //
-    //update: Now we also put a offset right before the "next_object" pointer
-    //        unlike the points whose space will be used when the object is allocated,
-    //        the offset will remain unchanged all the time to keep track of the address of the slab to achive a better dealloc speed.
+    //update: Slabs are aligned to their size, so the slab of an object is found by masking its address,
+    //        and the objects keep the alignment of their power of two size.
     fn from_addr(addr: usize) -> &'static mut Slab {
         unsafe { &mut *(addr as *mut Slab) }
     }
@@ -407,14 +1074,15 @@ impl Slab{
     }
 
     //This must be called when creating a new slab for a KmemCache
-    pub unsafe fn init(&self, _obj_size:usize, addr:usize,  _cache_addre:usize){
+    //The slab is 2^_order pages aligned to its size, the objects are packed from its start and the Slab header is at its end
+    pub unsafe fn init(&self, _obj_size:usize, _link_offset:usize, _order:usize, addr:usize,  _cache_addre:usize){
 
         debug!("Slab init addr{} next{} size{}", addr, 0, _obj_size);
 
         self.as_mut_ref().inuse = 0;
 
         self.as_mut_ref().start_addr = addr.clone();   
-        self.as_mut_ref().end_addr = addr.clone()+PAGE_SIZE;
+        self.as_mut_ref().end_addr = addr.clone()+slab_bytes(_order);
         
         self.as_mut_ref().cache_addr = _cache_addre;
 
@@ -422,29 +1090,70 @@ impl Slab{
         self.as_mut_ref().prev_slab = 0;
 
         self.as_mut_ref().obj_size = _obj_size;
-        let _obj_num = PAGE_SIZE/_obj_size;
-        let self_space = (core::mem::size_of::<Slab>() + (_obj_size - 1)) & (!(_obj_size - 1));
-        self.as_mut_ref().capacity = _obj_num - self_space/_obj_size;
+        self.as_mut_ref().link_offset = _link_offset;
+        self.as_mut_ref().capacity = Slab::capacity_of(_obj_size, _order);
         self.as_mut_ref().free = self.capacity;
 
-        self.as_mut_ref().free_head = addr + self_space;
+        self.as_mut_ref().free_head = addr;
 
-        let mut ptr:usize = addr + self_space;
+        let mut ptr:usize = addr;
         for _i in 1..self.capacity{
-            Obj::from_addr(ptr).next_obj = ptr + _obj_size;
-            Obj::from_addr(ptr).slab_addr =  self as *const Slab as usize ;
+            self.set_link(ptr, ptr + _obj_size);
             ptr = ptr + _obj_size;
         }
         //last object, set its next to 0
-        Obj::from_addr(ptr).next_obj = 0;
-        Obj::from_addr(ptr).slab_addr = self as *const Slab as usize ;
+        self.set_link(ptr, 0);
         
     }
+
+    //Return true if the object at addr is in the free list of the slab
+    fn is_free(&self, addr:usize) -> bool{
+        let mut ptr = self.free_head;
+        while ptr != 0{
+            if ptr == addr{
+                return true
+            }
+            ptr = self.link(ptr);
+        }
+        false
+    }
+
+    //Return the number of slabs in the list that starts with the slab at addr
+    fn count(addr:usize) -> usize{
+        let mut num = 0;
+        let mut ptr = addr;
+        while ptr != 0{
+            num += 1;
+            ptr = Slab::from_addr(ptr).next_slab;
+        }
+        num
+    }
+
+    //Return the number of objects of the given size in a slab of 2^order pages
+    fn capacity_of(obj_size:usize, order:usize) -> usize{
+        (slab_bytes(order) - core::mem::size_of::<Slab>()) / obj_size
+    }
+
+    //Return the next free object after the free object at addr
+    fn link(&self, addr:usize) -> usize{
+        unsafe { *((addr + self.link_offset) as *const usize) }
+    }
+
+    fn set_link(&self, addr:usize, next:usize){
+        unsafe { *((addr + self.link_offset) as *mut usize) = next; }
+    }
+
+    //Call f on every object of the slab, with the pointer the user gets for it
+    fn for_each_obj<F: FnMut(*mut u8)>(&self, mut f: F){
+        for _i in 0..self.capacity{
+            f((self.start_addr + _i * self.obj_size) as *mut u8);
+        }
+    }
     
 
     pub unsafe fn slab_alloc(&self) -> *mut u8{
-        let ret: *mut u8 = (self.free_head + core::mem::size_of::<usize>()) as *mut u8;
-        self.as_mut_ref().free_head = Obj::from_addr(self.free_head).next_obj;
+        let ret: *mut u8 = self.free_head as *mut u8;
+        self.as_mut_ref().free_head = self.link(self.free_head);
         self.as_mut_ref().inuse += 1;
         self.as_mut_ref().free -= 1;
         //debug!("alloc addr{} inuse{} free{} capa{}", self as *const Slab as usize, self.inuse,self.free,self.capacity);
@@ -456,7 +1165,7 @@ impl Slab{
     pub unsafe fn slab_dealloc(&self,addr:usize)->(usize,usize) {
         let tmp = self.free_head;
         self.as_mut_ref().free_head = addr;
-        Obj::from_addr(self.free_head).next_obj = tmp;
+        self.set_link(self.free_head, tmp);
         self.as_mut_ref().inuse -= 1;
         self.as_mut_ref().free += 1;
         //debug!("dealloc addr{} inuse{} free{} capa{}", self as *const Slab as usize, self.inuse,self.free,self.capacity);
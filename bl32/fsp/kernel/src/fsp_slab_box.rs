@@ -0,0 +1,102 @@
+//! This is a typed layer on top of the named caches of fsp_slab. A TypedCache<T> is a named cache
+//! for objects of type T, and it hands out SlabBox<T>s, which own their object like a Box<T> does
+//! and give it back to the cache when they are dropped. This way, code that keeps its objects in a
+//! cache never touches raw pointers or layouts. A SlabBox<T> borrows the TypedCache<T> it came
+//! from, so the cache can't be dropped before its objects, and dropping it destroys its cache.
+//!
+//! Bad things that should not occur:
+//!
+//! - Handing out an object that is not properly initialized
+//! - Giving an object back to a cache other than the one it came from
+//! - Giving an object back without dropping it, or dropping it twice
+
+use crate::entrypoints::FSP_SLAB;
+use crate::fsp_alloc::AllocError;
+use crate::fsp_slab::KmemCache;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// A named cache that only holds objects of type T.
+pub struct TypedCache<T> {
+    cache: &'static KmemCache,
+    _marker: PhantomData<T>,
+}
+
+/// An object of type T in a TypedCache<T>.
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a TypedCache<T>,
+}
+
+impl<T> TypedCache<T> {
+    /// Creates a named cache for objects of type T. Returns None if T is bigger than
+    /// KMEM_MAX_SIZE or no page can be obtained for it.
+    pub fn new(name: &str) -> Option<TypedCache<T>> {
+        let cache = unsafe {
+            FSP_SLAB.create_cache(
+                name,
+                core::mem::size_of::<T>(),
+                core::mem::align_of::<T>(),
+                None,
+                None,
+            )
+        }?;
+
+        Some(TypedCache {
+            cache,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Moves `value` into an object of the cache. Gives `value` back, with why it failed, if no
+    /// object can be allocated.
+    pub fn alloc(&self, value: T) -> Result<SlabBox<'_, T>, (T, AllocError)> {
+        let ptr = match NonNull::new(unsafe { self.cache.cache_alloc() } as *mut T) {
+            Some(ptr) => ptr,
+            None => return Err((value, AllocError::OutOfMemory)),
+        };
+        unsafe {
+            ptr.as_ptr().write(value);
+        }
+
+        Ok(SlabBox { ptr, cache: self })
+    }
+}
+
+impl<T> Drop for TypedCache<T> {
+    /// Destroys the cache. Every SlabBox<T> from it is gone by now, but if some were leaked with
+    /// mem::forget(), their objects are still in use and the cache is left in place for them.
+    fn drop(&mut self) {
+        unsafe {
+            FSP_SLAB.destroy_cache(self.cache, false);
+        }
+    }
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.cache_dealloc(self.ptr.as_ptr() as *mut u8);
+        }
+    }
+}
+
+// A SlabBox<T> owns its T like a Box<T> does, and the caches have their own locks.
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
@@ -24,10 +24,16 @@
 
 mod console;
 mod entrypoints;
-mod fsp_alloc;
-mod fsp_slab;
+pub mod fsp_alloc;
+pub mod fsp_arena;
+#[cfg(feature = "buddy")]
+mod fsp_buddy;
+pub mod fsp_global;
+pub mod fsp_slab;
+pub mod fsp_slab_box;
 mod log;
 mod qemu_constants;
+mod spinlock;
 
 /// SMC function IDs that FSP uses to signal various forms of completions
 /// to the secure payload dispatcher.
@@ -54,6 +60,15 @@ pub static FSP_SYSTEM_RESET_DONE: u64 = 0xf2000009;
 //#[no_mangle]
 //pub static FSP_HANDLE_SEL1_INTR_AND_RETURN: u64 = 0x2004; // currently only used by asm
 
+/// Identifiers for the FSP services that are implemented in Rust, as in fsp.h. A service is
+/// identified by the last 16 bits of the SMC function ID.
+pub const FSP_BARE_FID_MASK: u64 = 0xffff;
+pub const FSP_HEAP_STATS: u64 = 0x2005;
+pub const FSP_SLAB_INFO: u64 = 0x2006;
+
+/// SMC return value for an unknown request, as in smccc.h.
+pub const SMC_UNK: u64 = 0xffffffff;
+
 /// Definitions to help the assembler access the SMC/ERET args structure
 // TODO: These are currently duplicated from fsp_private.h
 //pub const FSP_ARGS_SIZE: usize = 0x40; // currently only used by asm
@@ -144,6 +159,8 @@ extern "C" fn set_smc_args(
 /// psci cpu_on request.
 #[no_mangle]
 pub extern "C" fn cpu_on_main_wrapper() -> &'static FspArgs {
+    entrypoints::fsp_cpu_on_main();
+
     /* Indicate to the SPD that we have completed turned ourselves on */
     set_smc_args(FSP_ON_DONE, 0, 0, 0, 0, 0, 0, 0)
 }
@@ -161,6 +178,8 @@ pub extern "C" fn cpu_off_main_wrapper(
     _arg6: u64,
     _arg7: u64,
 ) -> &'static FspArgs {
+    entrypoints::fsp_cpu_off_main();
+
     /* Indicate to the SPD that we have completed this request */
     set_smc_args(FSP_OFF_DONE, 0, 0, 0, 0, 0, 0, 0)
 }
@@ -179,6 +198,8 @@ pub extern "C" fn cpu_suspend_main_wrapper(
     _arg6: u64,
     _arg7: u64,
 ) -> &'static FspArgs {
+    entrypoints::fsp_cpu_suspend_main();
+
     /* Indicate to the SPD that we have completed this request */
     set_smc_args(FSP_SUSPEND_DONE, 0, 0, 0, 0, 0, 0, 0)
 }
@@ -250,14 +271,35 @@ pub extern "C" fn smc_handler_wrapper(
     _arg6: u64,
     _arg7: u64,
 ) -> &'static FspArgs {
+    #[cfg(feature = "heap_track")]
+    let track = entrypoints::smc_track_begin(func);
+
     /* Indicate to the SPD that we have completed this request */
-    set_smc_args(func, 0, arg1, arg2, 0, 0, 0, 0)
+    let args = match func & FSP_BARE_FID_MASK {
+        // arg1 selects which statistics to return in x2 and x3.
+        FSP_HEAP_STATS => match entrypoints::heap_stats_smc(arg1) {
+            Some((val0, val1)) => set_smc_args(func, 0, val0, val1, 0, 0, 0, 0),
+            None => set_smc_args(func, SMC_UNK, 0, 0, 0, 0, 0, 0),
+        },
+        // arg1 is the position of the cache and arg2 selects which statistics to return.
+        FSP_SLAB_INFO => match entrypoints::slab_info_smc(arg1, arg2) {
+            Some((val0, val1)) => set_smc_args(func, 0, val0, val1, 0, 0, 0, 0),
+            None => set_smc_args(func, SMC_UNK, 0, 0, 0, 0, 0, 0),
+        },
+        _ => set_smc_args(func, 0, arg1, arg2, 0, 0, 0, 0),
+    };
+
+    #[cfg(feature = "heap_track")]
+    entrypoints::smc_track_end(track);
+
+    args
 }
 
 /// FSP smc abort handler. This function is called when aborting a preempted
 /// yielding SMC request. It should cleanup all resources owned by the SMC
 /// handler such as locks or dynamically allocated memory so following SMC
-/// request are executed in a clean environment.
+/// request are executed in a clean environment. Spinlocks are only held with
+/// interrupts masked, so the aborted request can't have been holding one.
 #[no_mangle]
 pub extern "C" fn abort_smc_handler_wrapper(
     _func: u64,
@@ -269,6 +311,10 @@ pub extern "C" fn abort_smc_handler_wrapper(
     _arg6: u64,
     _arg7: u64,
 ) -> &'static FspArgs {
+    // The aborted request has been cleaned up, so whatever it still has allocated is leaked.
+    #[cfg(feature = "heap_track")]
+    entrypoints::smc_track_end(entrypoints::SmcTrack::NONE);
+
     set_smc_args(FSP_ABORT_DONE, 0, 0, 0, 0, 0, 0, 0)
 }
 
@@ -323,6 +369,10 @@ extern "C" {
     static fsp_vector_table: FspVectors;
 
     fn plat_my_core_pos() -> u32;
+
+    fn fsp_irq_save() -> u64;
+
+    fn fsp_irq_restore(daif: u64);
 }
 
 pub fn bl32_end() -> usize {
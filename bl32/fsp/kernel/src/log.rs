@@ -17,10 +17,20 @@ macro_rules! debug {
     };
 
     ( $x:literal, $($y:expr),+ ) => {
+        #[cfg(feature = "debug")]
+        {
+            // This formats straight to the console rather than with alloc::format, so that the
+            // allocators can report problems in the middle of an allocation.
+            #[allow(unused_unsafe)] // to avoid nested unsafe warnings
+            unsafe {
+                use core::fmt::Write;
+                writeln!(&mut crate::entrypoints::FSP_CONSOLE, concat!("FSP DEBUG: ", $x), $($y),+)
+                    .unwrap();
+            }
+        }
+        #[cfg(not(feature = "debug"))]
         {
-            // TODO: verify if we need a global allocator with alloc::format
-            //assert!(crate::entrypoints::FSP_ALLOC.is_initialized(), "Global allocator is not initialized");
-            debug!(alloc::format!($x, $($y),+));
+            let _ = ($(&$y),+);
         }
     };
 }
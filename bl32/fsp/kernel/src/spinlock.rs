@@ -0,0 +1,116 @@
+//! This is a spinlock for data that is shared between cores. It is built on the atomics that the
+//! target supports, and it is not reentrant.
+//!
+//! IRQs and FIQs are masked on a core for as long as it holds a lock. Yielding SMCs run with them
+//! unmasked, and a lock held by a preempted SMC would make any SMC that then comes in on the same
+//! core spin forever, and would never be released if the preempted SMC is aborted. IrqGuard does
+//! the same for data that only the current core touches, which needs no lock.
+//!
+//! Bad things that should not occur:
+//!
+//! - Two cores holding the lock at the same time
+//! - Accesses to the protected data moving out of the critical section
+//! - Leaving the lock held after the critical section
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The data that the lock protects is only reachable through a guard, which is what hands out
+/// references to it. A lock that only serializes code, with its data kept elsewhere, protects ().
+pub struct SpinLock<T = ()> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// The guard makes sure that a single core at a time gets to the data.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+/// Holding one of these means holding the lock. The lock is released when it is dropped.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // Dropped after the lock is released.
+    _irq: IrqGuard,
+}
+
+/// Holding one of these keeps IRQs and FIQs masked on the current core. They go back to how they
+/// were when it is dropped, so guards can be nested as long as they are dropped in reverse order.
+pub struct IrqGuard {
+    daif: u64,
+}
+
+impl IrqGuard {
+    pub fn new() -> IrqGuard {
+        IrqGuard {
+            daif: unsafe { crate::fsp_irq_save() },
+        }
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        unsafe { crate::fsp_irq_restore(self.daif) }
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Spins until the lock is acquired. Interrupts are only masked while the lock is held, not
+    /// while waiting for it.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            // Wait for the lock to look free before trying again, so that we don't keep taking
+            // the cache line away from the holder.
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Acquires the lock only if it is free right now.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(SpinLockGuard {
+                lock: self,
+                _irq: irq,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
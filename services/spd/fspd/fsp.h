@@ -48,6 +48,8 @@
 #define FSP_MUL     0x2002
 #define FSP_DIV     0x2003
 #define FSP_HANDLE_SEL1_INTR_AND_RETURN 0x2004
+#define FSP_HEAP_STATS  0x2005
+#define FSP_SLAB_INFO   0x2006
 
 /*
  * Identify a FSP service from function ID filtering the last 16 bits from the
@@ -75,7 +77,7 @@
  * Total number of function IDs implemented for services offered to NS clients.
  * The function IDs are defined above
  */
-#define FSP_NUM_FID     0x5
+#define FSP_NUM_FID     0x7
 
 /* FSP implementation version numbers */
 #define FSP_VERSION_MAJOR   0x0 /* Major version */
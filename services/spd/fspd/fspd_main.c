@@ -489,18 +489,22 @@ static uintptr_t fspd_smc_handler(uint32_t smc_fid,
 
         /*
          * Request from non-secure client to perform an
-         * arithmetic operation or response from secure
-         * payload to an earlier request.
+         * arithmetic operation or to read the heap or slab
+         * statistics, or response from secure payload to an earlier request.
          */
     case FSP_FAST_FID(FSP_ADD):
     case FSP_FAST_FID(FSP_SUB):
     case FSP_FAST_FID(FSP_MUL):
     case FSP_FAST_FID(FSP_DIV):
+    case FSP_FAST_FID(FSP_HEAP_STATS):
+    case FSP_FAST_FID(FSP_SLAB_INFO):
 
     case FSP_YIELD_FID(FSP_ADD):
     case FSP_YIELD_FID(FSP_SUB):
     case FSP_YIELD_FID(FSP_MUL):
     case FSP_YIELD_FID(FSP_DIV):
+    case FSP_YIELD_FID(FSP_HEAP_STATS):
+    case FSP_YIELD_FID(FSP_SLAB_INFO):
         if (ns) {
             /*
              * This is a fresh request from the non-secure client.